@@ -2,6 +2,8 @@
 
 let
   cfg = config.services.wikimark;
+  settingsFormat = pkgs.formats.toml { };
+  configFile = settingsFormat.generate "wikimark.toml" cfg.settings;
 in
 {
   options.services.wikimark = {
@@ -21,6 +23,29 @@ in
       description = "Address to bind to.";
     };
 
+    socket = lib.mkOption {
+      type = lib.types.nullOr lib.types.str;
+      default = null;
+      example = "/run/wikimark/wikimark.sock";
+      description = "Unix socket to listen on instead of the address and port.";
+    };
+
+    socketMode = lib.mkOption {
+      type = lib.types.str;
+      default = "660";
+      description = "Permissions of the Unix socket, in octal.";
+    };
+
+    socketActivation = lib.mkOption {
+      type = lib.types.bool;
+      default = false;
+      description = ''
+        Let systemd open the socket and start wikimark on the first
+        connection. Listens on `socket` if set, otherwise on the address
+        and port.
+      '';
+    };
+
     repoPath = lib.mkOption {
       type = lib.types.path;
       default = "/var/lib/wikimark/repo";
@@ -34,6 +59,26 @@ in
       description = "URL prefix for linking to commits.";
     };
 
+    settings = lib.mkOption {
+      type = settingsFormat.type;
+      default = { };
+      example = lib.literalExpression ''
+        {
+          name = "Team Wiki";
+          base_url = "https://wiki.example.com";
+          auth.header = "x-forwarded-user";
+          render.theme = "InspiredGitHub";
+          features.journal = false;
+          authors.jdoe = "Jane Doe <jane@example.com>";
+        }
+      '';
+      description = ''
+        Configuration written to a `wikimark.toml` file. It takes precedence
+        over the `wikimark.toml` of the wiki repo, the options above take
+        precedence over it.
+      '';
+    };
+
     user = lib.mkOption {
       type = lib.types.str;
       default = "wikimark";
@@ -66,10 +111,24 @@ in
 
     users.groups.${cfg.group} = lib.mkIf (cfg.group == "wikimark") { };
 
+    systemd.sockets.wikimark = lib.mkIf cfg.socketActivation {
+      description = "Wikimark wiki server socket";
+      wantedBy = [ "sockets.target" ];
+      listenStreams = [
+        (if cfg.socket != null then cfg.socket else "${cfg.address}:${toString cfg.port}")
+      ];
+      socketConfig = lib.optionalAttrs (cfg.socket != null) {
+        SocketMode = cfg.socketMode;
+        SocketUser = cfg.user;
+        SocketGroup = cfg.group;
+      };
+    };
+
     systemd.services.wikimark = {
       description = "Wikimark wiki server";
       after = [ "network.target" ];
-      wantedBy = [ "multi-user.target" ];
+      requires = lib.optional cfg.socketActivation "wikimark.socket";
+      wantedBy = lib.optional (!cfg.socketActivation) "multi-user.target";
 
       serviceConfig = {
         Type = "simple";
@@ -80,8 +139,14 @@ in
             --port ${toString cfg.port} \
             --address ${cfg.address} \
             --repo ${cfg.repoPath} \
-            --commit-url-prefix "${cfg.commitUrlPrefix}"
+            --commit-url-prefix "${cfg.commitUrlPrefix}" \
+            --config ${configFile} \
+            ${lib.optionalString (cfg.socket != null && !cfg.socketActivation)
+              "--socket ${cfg.socket} --socket-mode ${cfg.socketMode}"}
         '';
+        RuntimeDirectory = lib.mkIf (cfg.socket != null && !cfg.socketActivation) "wikimark";
+        # Reloads the TLS certificate
+        ExecReload = lib.mkIf (cfg.settings ? tls) "${pkgs.coreutils}/bin/kill -HUP $MAINPID";
         Restart = "on-failure";
         RestartSec = "5s";
 
@@ -0,0 +1,179 @@
+use minijinja::Environment;
+use serde_derive::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::export::resolve;
+use crate::git::Repo;
+use crate::page::{find_redirect, get_page, list_files, TocItem};
+use crate::{md2html, STATIC_ASSETS};
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+/// Pages of the wiki that other routes than `/page/` link to.
+const ROUTES: [&str; 6] = ["/", "/all", "/tags", "/journal", "/changelog", "/edit"];
+
+/// A link that doesn't lead anywhere.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct BrokenLink {
+    /// Page the link is on.
+    pub page: String,
+    pub private: bool,
+    pub href: String,
+    pub reason: String,
+}
+
+fn section_ids(item: &TocItem, ids: &mut BTreeSet<String>) {
+    ids.insert(item.section.link.clone());
+    for c in &item.children {
+        section_ids(c, ids);
+    }
+}
+
+/// `s` with its HTML entities for `/` and `&` and its `%xx` escapes decoded.
+pub fn decode(s: &str) -> String {
+    let s = s.replace("&#x2f;", "/").replace("&amp;", "&");
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok());
+        match hex
+            .filter(|_| bytes[i] == b'%')
+            .map(|h| u8::from_str_radix(h, 16))
+        {
+            Some(Ok(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            _ => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Targets of the `href` and `src` attributes in `html`.
+fn links(html: &str) -> Vec<String> {
+    let mut ret = vec![];
+    for attr in [" href=\"", " src=\""] {
+        for (start, _) in html.match_indices(attr) {
+            let value = &html[start + attr.len()..];
+            if let Some(len) = value.find('"') {
+                ret.push(decode(&value[..len]));
+            }
+        }
+    }
+    ret
+}
+
+struct Target {
+    private: bool,
+    sections: BTreeSet<String>,
+    links: Vec<String>,
+}
+
+/// Why the link `href` on the page at `link` is broken, if it is.
+fn check_link(
+    repo: &Repo,
+    pages: &BTreeMap<String, Target>,
+    link: &str,
+    href: &str,
+) -> Option<String> {
+    let (path, anchor) = href.split_once('#').unwrap_or((href, ""));
+    let path = path.split('?').next().unwrap_or_default();
+    if path.starts_with("//") || path.contains(':') {
+        return None;
+    }
+    let target = if path.is_empty() {
+        link.to_owned()
+    } else {
+        let url = resolve(&format!("/page/{link}"), path);
+        if let Some(file) = url.strip_prefix("/static/") {
+            let found = file == "wiki.css" || STATIC_ASSETS.get_file(file).is_some();
+            return (!found).then(|| format!("no static file `{file}`"));
+        }
+        let Some(target) = url.strip_prefix("/page/") else {
+            let known = ROUTES.contains(&url.as_str())
+                || url.starts_with("/tags/")
+                || url.starts_with("/journal/");
+            return (!known).then(|| format!("no route `{url}`"));
+        };
+        let name = target.rsplit('/').next().unwrap_or_default();
+        if name.contains('.') {
+            // Attachments are files of the wiki repo
+            let found = repo.get_blob(target).is_ok();
+            return (!found).then(|| format!("no file `{target}`"));
+        }
+        if pages.contains_key(target) {
+            target.to_owned()
+        } else {
+            match find_redirect(repo, target) {
+                Ok(Some(t)) if pages.contains_key(&t) => t,
+                _ => return Some(format!("no page `{target}`")),
+            }
+        }
+    };
+    if anchor.is_empty() || pages[&target].sections.contains(anchor) {
+        None
+    } else {
+        Some(format!("no section `{anchor}` in `{target}`"))
+    }
+}
+
+/// Every internal link of the wiki that doesn't lead to a page, section or
+/// file, including links on private pages. Absolute links starting with
+/// `base_url` are internal links too.
+pub fn check(repo: &Repo, env: &Environment, base_url: &str) -> Result<Vec<BrokenLink>> {
+    let mut pages = BTreeMap::new();
+    let entries = list_files(repo, "", true)?.into_iter().map(|e| e.link);
+    for link in std::iter::once(String::new()).chain(entries) {
+        let (md, _) = get_page(repo, &link)?;
+        let page = md2html::parse(repo, env, &link, &md.content, &md.meta);
+        let mut sections = BTreeSet::new();
+        section_ids(&page.toc.0, &mut sections);
+        let target = Target {
+            private: md.meta.private,
+            sections,
+            links: links(&page.content),
+        };
+        pages.insert(link, target);
+    }
+    let base_path = md2html::base_path(env);
+    let mut broken = vec![];
+    for (link, page) in &pages {
+        for href in &page.links {
+            let mut internal = href.as_str();
+            for base in [base_url.trim_end_matches('/'), &base_path] {
+                match internal.strip_prefix(base) {
+                    Some(path) if !base.is_empty() && path.starts_with('/') => internal = path,
+                    _ => {}
+                }
+            }
+            if let Some(reason) = check_link(repo, &pages, link, internal) {
+                broken.push(BrokenLink {
+                    page: link.clone(),
+                    private: page.private,
+                    href: href.clone(),
+                    reason,
+                });
+            }
+        }
+    }
+    Ok(broken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_targets() {
+        let html =
+            "<a href=\"/page/a&#x2f;b%20c#x\"><img src=\"d.png\" alt=\"\"><a href=\"e?x=1&amp;y\">";
+        assert_eq!(links(html), ["/page/a/b c#x", "e?x=1&y", "d.png"]);
+    }
+}
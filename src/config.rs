@@ -0,0 +1,242 @@
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::git::Repo;
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+/// Name of the configuration file in the wiki repo.
+pub const CONFIG_FILE: &str = "wikimark.toml";
+
+/// Settings that `wikimark.toml` in the wiki repo can change, as they only
+/// affect how the wiki looks. The others are for whoever runs the server.
+const REPO_KEYS: [&str; 4] = ["name", "commit_url_prefix", "journal_dir", "render"];
+
+/// Settings from `wikimark.toml`. Command line flags and environment
+/// variables take precedence over them.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Name of the wiki, shown in page titles.
+    pub name: String,
+    /// Public URL of the wiki. Links to it are checked like internal links.
+    pub base_url: String,
+    /// URL path the wiki is served under, like `/wiki`.
+    pub base_path: String,
+    pub port: u16,
+    pub address: String,
+    /// Unix socket to listen on instead of the address and port.
+    pub socket: String,
+    /// Permissions of the Unix socket, in octal.
+    pub socket_mode: String,
+    pub tls: Tls,
+    /// Seconds to wait for running requests when shutting down.
+    pub shutdown_timeout: u64,
+    pub repo: String,
+    pub commit_url_prefix: String,
+    /// Directory of journal entries, `{user}` is replaced with the user name.
+    pub journal_dir: String,
+    pub auth: Auth,
+    pub render: Render,
+    pub features: Features,
+    /// Commit authors for user names, as `Name <email>`.
+    pub authors: BTreeMap<String, String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            name: "Wikimark".to_owned(),
+            base_url: String::new(),
+            base_path: String::new(),
+            port: 3000,
+            address: "127.0.0.1".to_owned(),
+            socket: String::new(),
+            socket_mode: "660".to_owned(),
+            tls: Tls::default(),
+            shutdown_timeout: 30,
+            repo: "repo".to_owned(),
+            commit_url_prefix: String::new(),
+            journal_dir: "journal".to_owned(),
+            auth: Auth::default(),
+            render: Render::default(),
+            features: Features::default(),
+            authors: BTreeMap::new(),
+        }
+    }
+}
+
+/// Serve HTTPS with a PEM certificate and key, reloaded on SIGHUP.
+#[derive(Deserialize, Clone, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tls {
+    pub cert: String,
+    pub key: String,
+    /// Address to redirect plain HTTP from, like `0.0.0.0:80`.
+    pub redirect_http: String,
+}
+
+impl Tls {
+    pub fn enabled(&self) -> bool {
+        !self.cert.is_empty() || !self.key.is_empty()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Auth {
+    /// Header set by the reverse proxy to the name of the logged in user.
+    pub header: String,
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Auth {
+            header: "remote-user".to_owned(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Render {
+    /// Syntax highlighting theme of code blocks.
+    pub theme: String,
+    /// Show the table of contents next to pages.
+    pub toc: bool,
+}
+
+impl Default for Render {
+    fn default() -> Self {
+        Render {
+            theme: "base16-ocean.dark".to_owned(),
+            toc: true,
+        }
+    }
+}
+
+/// Parts of the wiki that can be turned off.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Features {
+    pub journal: bool,
+    pub changelog: bool,
+    /// The broken links page.
+    pub check: bool,
+    /// Prometheus metrics at `/metrics`.
+    pub metrics: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Features {
+            journal: true,
+            changelog: true,
+            check: true,
+            metrics: true,
+        }
+    }
+}
+
+impl Config {
+    /// Author of the commits made by `user`.
+    pub fn author(&self, user: &str) -> String {
+        self.authors
+            .get(user)
+            .cloned()
+            .unwrap_or_else(|| user.to_owned())
+    }
+}
+
+/// `path` with a leading slash and without a trailing one, empty for the
+/// root.
+pub fn normalize_base_path(path: &str) -> String {
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        String::new()
+    } else {
+        format!("/{path}")
+    }
+}
+
+/// Merge the tables of `over` into `base`, replacing the other values.
+fn merge(base: &mut toml::Table, over: toml::Table) {
+    for (key, value) in over {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(b)), toml::Value::Table(o)) => merge(b, o),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Settings of the `wikimark.toml` of the wiki repo. Anyone who can push to
+/// the repo can change them, so they can't be the ones of the server.
+fn repo_table(content: &str) -> Result<toml::Table> {
+    let table: toml::Table = content
+        .parse()
+        .map_err(|e| anyhow::anyhow!("{CONFIG_FILE} in the wiki repo: {e}"))?;
+    if let Some(key) = table.keys().find(|k| !REPO_KEYS.contains(&k.as_str())) {
+        anyhow::bail!(
+            "{CONFIG_FILE} in the wiki repo: `{key}` can only be set in the configuration file"
+        );
+    }
+    Ok(table)
+}
+
+/// Settings of `wikimark.toml` in `repo`, overridden by the ones of the
+/// file at `path`.
+pub fn load(path: Option<&Path>, repo: Option<&Repo>) -> Result<Config> {
+    let mut table = toml::Table::new();
+    if let Some(content) = repo.and_then(|r| r.get_file(CONFIG_FILE).ok()) {
+        merge(&mut table, repo_table(&content)?);
+    }
+    if let Some(path) = path {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+        let file_table = content
+            .parse()
+            .map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+        merge(&mut table, file_table);
+    }
+    Ok(table.try_into()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_overrides_repo() {
+        let mut table =
+            repo_table("name = \"Docs\"\n[render]\ntoc = false\ntheme = \"x\"\n").unwrap();
+        merge(
+            &mut table,
+            "port = 8080\n[render]\ntheme = \"y\"\n[features]\ncheck = false\n"
+                .parse()
+                .unwrap(),
+        );
+        let config: Config = table.try_into().unwrap();
+        assert_eq!(config.name, "Docs");
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.address, "127.0.0.1");
+        assert!(!config.render.toc);
+        assert_eq!(config.render.theme, "y");
+        assert!(!config.features.check);
+        assert!(config.features.changelog);
+    }
+
+    #[test]
+    fn repo_cant_set_server_keys() {
+        for key in [
+            "[auth]\nheader = \"x\"",
+            "repo = \"/\"",
+            "port = 1",
+            "[features]",
+        ] {
+            assert!(repo_table(key).is_err(), "{key}");
+        }
+    }
+}
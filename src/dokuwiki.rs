@@ -0,0 +1,453 @@
+use flate2::read::GzDecoder;
+use slug::slugify;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::import::{markdown_table, History, Revision};
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+/// Wiki path of the page with the DokuWiki id `id`, under `base`.
+/// Namespaces become directories.
+fn page_link(base: &str, id: &str) -> String {
+    let path: Vec<_> = id
+        .split(':')
+        .map(slugify)
+        .filter(|s| !s.is_empty())
+        .collect();
+    format!("{base}{}", path.join("/"))
+}
+
+/// Absolute id of the link target `target` found in namespace `ns`.
+fn resolve_id(ns: &str, target: &str) -> String {
+    let target = target.trim();
+    if let Some(id) = target.strip_prefix(':') {
+        id.to_owned()
+    } else if let Some(id) = target
+        .strip_prefix(".:")
+        .or_else(|| target.strip_prefix('.'))
+    {
+        format!("{ns}:{id}")
+    } else if target.contains(':') || ns.is_empty() {
+        target.to_owned()
+    } else {
+        format!("{ns}:{target}")
+    }
+}
+
+/// Replace pairs of `mark` with `open` and `close`. Marks right after a
+/// colon are left alone, to keep URLs intact.
+fn pairs(text: &str, mark: &str, open: &str, close: &str) -> String {
+    let found: Vec<_> = text
+        .match_indices(mark)
+        .map(|(i, _)| i)
+        .filter(|&i| !text[..i].ends_with(':'))
+        .collect();
+    let mut out = String::new();
+    let mut last = 0;
+    for pair in found.chunks_exact(2) {
+        out.push_str(&text[last..pair[0]]);
+        out.push_str(open);
+        out.push_str(&text[pair[0] + mark.len()..pair[1]]);
+        out.push_str(close);
+        last = pair[1] + mark.len();
+    }
+    out.push_str(&text[last..]);
+    out
+}
+
+/// Convert formatting in text without links or code.
+fn format(text: &str) -> String {
+    let text = pairs(text, "//", "*", "*");
+    let text = pairs(&text, "__", "<u>", "</u>");
+    text.replace("<del>", "~~")
+        .replace("</del>", "~~")
+        .replace("\\\\ ", "<br>")
+        .trim_end_matches("\\\\")
+        .to_owned()
+}
+
+/// Convert inline DokuWiki markup: links, images, monospace and emphasis.
+fn inline(base: &str, ns: &str, line: &str, tags: &mut Vec<String>) -> String {
+    let mut out = String::new();
+    let mut rest = line;
+    loop {
+        let next = ["[[", "{{", "''", "%%"]
+            .into_iter()
+            .filter_map(|m| Some((rest.find(m)?, m)))
+            .min();
+        let Some((start, mark)) = next else {
+            break;
+        };
+        let close = match mark {
+            "[[" => "]]",
+            "{{" => "}}",
+            m => m,
+        };
+        let Some(len) = rest[start + 2..].find(close) else {
+            break;
+        };
+        out.push_str(&format(&rest[..start]));
+        let inner = &rest[start + 2..start + 2 + len];
+        rest = &rest[start + 2 + len + 2..];
+        match mark {
+            "''" => out.push_str(&format!("`{}`", inner.replace("%%", ""))),
+            "%%" => out.push_str(inner),
+            "{{" => {
+                if let Some(list) = inner.strip_prefix("tag>") {
+                    tags.extend(list.split_whitespace().map(|t| t.replace('_', " ")));
+                    continue;
+                }
+                let (src, caption) = inner.split_once('|').unwrap_or((inner, ""));
+                let src = src.trim().split('?').next().unwrap_or_default();
+                let src = match src.contains("://") {
+                    true => src.to_owned(),
+                    false => resolve_id(ns, src).replace(':', "/"),
+                };
+                out.push_str(&format!("![{}](<{src}>)", caption.trim()));
+            }
+            _ => {
+                let (target, label) = match inner.split_once('|') {
+                    Some((t, l)) => (t.trim(), Some(l.trim())),
+                    None => (inner.trim(), None),
+                };
+                let label = label.unwrap_or(target);
+                if target.contains("://") || target.starts_with("mailto:") {
+                    out.push_str(&format!("[{label}]({target})"));
+                    continue;
+                }
+                let (id, section) = target.split_once('#').unwrap_or((target, ""));
+                let anchor = match section {
+                    "" => String::new(),
+                    s => format!("#{}", slugify(s)),
+                };
+                if id.is_empty() {
+                    out.push_str(&format!("[{label}]({anchor})"));
+                } else {
+                    let link = page_link(base, &resolve_id(ns, id));
+                    out.push_str(&format!("[{label}](/page/{link}{anchor})"));
+                }
+            }
+        }
+    }
+    out.push_str(&format(rest));
+    out
+}
+
+/// Cells of a table row, split on `^` and `|` outside of links and media,
+/// whose labels and captions also follow a `|`.
+fn split_cells(row: &str) -> Vec<&str> {
+    let mut cells = vec![];
+    let mut depth = 0usize;
+    let mut start = 0;
+    let mut chars = row.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '[' | '{' if chars.peek().map(|(_, n)| *n) == Some(c) => {
+                depth += 1;
+                chars.next();
+            }
+            ']' | '}' if depth > 0 && chars.peek().map(|(_, n)| *n) == Some(c) => {
+                depth -= 1;
+                chars.next();
+            }
+            '^' | '|' if depth == 0 => {
+                cells.push(&row[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    cells.push(&row[start..]);
+    cells
+}
+
+/// Convert DokuWiki markup of a page in namespace `ns` to markdown. Links
+/// point to pages under `base`, tags of the tag plugin are returned.
+pub fn convert(text: &str, base: &str, ns: &str) -> (String, Vec<String>) {
+    let mut out = String::new();
+    let mut tags = vec![];
+    let mut table: Vec<Vec<String>> = vec![];
+    // Closing tag of the code block we are in
+    let mut code: Option<&str> = None;
+    let mut indented = false;
+    for line in text.lines() {
+        if let Some(end) = code {
+            match line.find(end) {
+                Some(i) => {
+                    out.push_str(&line[..i]);
+                    if i > 0 {
+                        out.push('\n');
+                    }
+                    out.push_str("```\n");
+                    code = None;
+                }
+                None => {
+                    out.push_str(line);
+                    out.push('\n');
+                }
+            }
+            continue;
+        }
+        let trimmed = line.trim();
+        let is_row = trimmed.starts_with(['^', '|']);
+        if !table.is_empty() && !is_row {
+            out.push_str(&markdown_table(&std::mem::take(&mut table)));
+        }
+        let list = line.starts_with("  ") && trimmed.starts_with(['*', '-']);
+        if indented && (!line.starts_with("  ") || list) {
+            out.push_str("```\n");
+            indented = false;
+        }
+        if let Some((tag, end)) = [("<code", "</code>"), ("<file", "</file>")]
+            .into_iter()
+            .find(|(tag, _)| trimmed.starts_with(tag))
+            && let Some(open) = trimmed.find('>')
+        {
+            let lang = trimmed[tag.len()..open].split_whitespace().next();
+            let lang = lang.filter(|l| *l != "-").unwrap_or_default();
+            out.push_str(&format!("```{lang}\n"));
+            let rest = &trimmed[open + 1..];
+            match rest.find(end) {
+                Some(i) => out.push_str(&format!("{}\n```\n", &rest[..i])),
+                None => {
+                    if !rest.is_empty() {
+                        out.push_str(rest);
+                        out.push('\n');
+                    }
+                    code = Some(end);
+                }
+            }
+        } else if is_row {
+            let cells = trimmed[1..].trim_end_matches(['^', '|']);
+            table.push(
+                split_cells(cells)
+                    .into_iter()
+                    .map(|c| inline(base, ns, c.trim(), &mut tags))
+                    .collect(),
+            );
+        } else if list {
+            let depth = (line.len() - line.trim_start().len()) / 2;
+            let indent = "    ".repeat(depth.saturating_sub(1));
+            let item = inline(base, ns, trimmed[1..].trim(), &mut tags);
+            let mark = if trimmed.starts_with('-') { "1." } else { "-" };
+            out.push_str(&format!("{indent}{mark} {item}\n"));
+        } else if line.starts_with("  ") && !trimmed.is_empty() {
+            if !indented {
+                out.push_str("```\n");
+                indented = true;
+            }
+            out.push_str(&line[2..]);
+            out.push('\n');
+        } else if trimmed.starts_with("----") {
+            out.push_str("---\n");
+        } else if trimmed.len() > 2 && trimmed.starts_with("==") && trimmed.ends_with("==") {
+            let marks = trimmed.len() - trimmed.trim_start_matches('=').len();
+            let title = inline(base, ns, trimmed.trim_matches('=').trim(), &mut tags);
+            out.push_str(&format!("{} {title}\n", "#".repeat(7 - marks.clamp(1, 6))));
+        } else {
+            let line = ["~~NOTOC~~", "~~NOCACHE~~"]
+                .iter()
+                .fold(line.to_owned(), |l, word| l.replace(word, ""));
+            let line = inline(base, ns, &line, &mut tags);
+            if line.trim().is_empty() && !trimmed.is_empty() {
+                // Lines left empty by the tag plugin or macros
+                continue;
+            }
+            out.push_str(&line);
+            out.push('\n');
+        }
+    }
+    if !table.is_empty() {
+        out.push_str(&markdown_table(&table));
+    }
+    if code.is_some() || indented {
+        out.push_str("```\n");
+    }
+    (out, tags)
+}
+
+/// Title of a page: its first heading, or its name.
+fn title(text: &str, name: &str) -> String {
+    text.lines()
+        .map(str::trim)
+        .find(|l| l.starts_with("==") && l.ends_with("=="))
+        .map(|l| l.trim_matches('=').trim().to_owned())
+        .unwrap_or_else(|| name.replace('_', " "))
+}
+
+fn read_gz(path: &Path) -> Result<String> {
+    let mut text = String::new();
+    GzDecoder::new(std::fs::File::open(path)?).read_to_string(&mut text)?;
+    Ok(text)
+}
+
+/// Revisions of the page `pages/{ns}/{name}.txt`, from its changelog and the
+/// old versions in the attic.
+fn page_history(
+    data: &Path,
+    ns: &[String],
+    name: &str,
+    base: &str,
+    author: &str,
+    history: &mut History,
+) -> Result<()> {
+    let dir: PathBuf = ns.iter().collect();
+    let current = data.join("pages").join(&dir).join(format!("{name}.txt"));
+    let changes = data.join("meta").join(&dir).join(format!("{name}.changes"));
+    let id = ns
+        .iter()
+        .map(String::as_str)
+        .chain([name])
+        .collect::<Vec<_>>()
+        .join(":");
+    let ns = ns.join(":");
+    let link = page_link(base, &id);
+
+    // Timestamp, author and summary of every change
+    let mut log = vec![];
+    if let Ok(changes) = std::fs::read_to_string(&changes) {
+        for line in changes.lines() {
+            let fields: Vec<_> = line.split('\t').collect();
+            let (Some(ts), Some(kind)) = (fields.first(), fields.get(2)) else {
+                continue;
+            };
+            let Ok(ts) = ts.parse::<i64>() else {
+                continue;
+            };
+            if *kind == "D" {
+                continue;
+            }
+            let user = match fields.get(4).copied().unwrap_or_default() {
+                "" => fields.get(1).copied().unwrap_or(author),
+                user => user,
+            };
+            let summary = fields.get(5).copied().unwrap_or_default();
+            log.push((ts, user.to_owned(), summary.to_owned()));
+        }
+    }
+    if log.is_empty() {
+        let modified = std::fs::metadata(&current)?.modified()?;
+        let ts = modified.duration_since(std::time::UNIX_EPOCH)?.as_secs() as i64;
+        log.push((ts, author.to_owned(), String::new()));
+    }
+
+    let last = log.len() - 1;
+    for (i, (ts, user, summary)) in log.into_iter().enumerate() {
+        let attic = data
+            .join("attic")
+            .join(&dir)
+            .join(format!("{name}.{ts}.txt.gz"));
+        let text = if attic.exists() {
+            read_gz(&attic)?
+        } else if i == last {
+            std::fs::read_to_string(&current)?
+        } else {
+            tracing::warn!("{}: missing, revision skipped", attic.display());
+            continue;
+        };
+        let (content, tags) = convert(&text, base, &ns);
+        history.revisions.push(Revision {
+            link: link.clone(),
+            title: title(&text, name),
+            tags,
+            content,
+            author: user,
+            timestamp: ts,
+            comment: summary,
+        });
+    }
+    Ok(())
+}
+
+fn walk(
+    data: &Path,
+    ns: &mut Vec<String>,
+    base: &str,
+    author: &str,
+    history: &mut History,
+) -> Result<()> {
+    let dir = data.join("pages").join(ns.iter().collect::<PathBuf>());
+    let mut entries: Vec<_> = std::fs::read_dir(dir)?.collect::<std::io::Result<_>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for e in entries {
+        let name = e.file_name().to_string_lossy().into_owned();
+        if e.file_type()?.is_dir() {
+            ns.push(name);
+            walk(data, ns, base, author, history)?;
+            ns.pop();
+        } else if let Some(name) = name.strip_suffix(".txt") {
+            page_history(data, ns, name, base, author, history)?;
+        }
+    }
+    Ok(())
+}
+
+/// Read every page of a DokuWiki data directory with all its revisions, for
+/// pages under `base`. Changes without an author are attributed to `author`.
+pub fn read_data_dir(path: &Path, base: &str, author: &str) -> Result<History> {
+    let data = [path.to_owned(), path.join("data")]
+        .into_iter()
+        .find(|d| d.join("pages").is_dir())
+        .ok_or_else(|| anyhow::anyhow!("{}: no DokuWiki pages directory", path.display()))?;
+    let mut history = History::default();
+    walk(&data, &mut vec![], base, author, &mut history)?;
+    Ok(history)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_cells() {
+        let (md, _) = convert(
+            "^ Page ^ Logo ^\n| [[ns:page|Label]] | {{logo.png|The logo}} |\n",
+            "",
+            "",
+        );
+        let rows: Vec<_> = md.lines().collect();
+        assert_eq!(rows[0], "| Page | Logo |");
+        assert_eq!(rows[2].matches(" | ").count(), 1);
+        assert!(rows[2].starts_with("| [Label](/page/ns/page) | "));
+        assert!(rows[2].contains("The logo"));
+    }
+
+    #[test]
+    fn markup() {
+        let text = concat!(
+            "====== Setup ======\n",
+            "Run **this** on //the// [[tools|build tools]] see [[https://example.com|docs]].\n",
+            "  * one\n",
+            "    * nested ''a//b''\n",
+            "  - first\n",
+            "<code rust>\n",
+            "fn main() {}\n",
+            "</code>\n",
+            "^ A ^ B ^\n",
+            "| 1 | [[:other#Part]] |\n",
+            "{{tag>ops on_call}}\n",
+        );
+        let (md, tags) = convert(text, "old/", "team");
+        assert_eq!(
+            md,
+            concat!(
+                "# Setup\n",
+                "Run **this** on *the* [build tools](/page/old/team/tools) ",
+                "see [docs](https://example.com).\n",
+                "- one\n",
+                "    - nested `a//b`\n",
+                "1. first\n",
+                "```rust\n",
+                "fn main() {}\n",
+                "```\n",
+                "| A | B |\n",
+                "| --- | --- |\n",
+                "| 1 | [:other#Part](/page/old/other#part) |\n",
+            )
+        );
+        assert_eq!(tags, ["ops", "on call"]);
+        assert_eq!(resolve_id("a:b", ".:c"), "a:b:c");
+        assert_eq!(resolve_id("a", "x:y"), "x:y");
+    }
+}
@@ -3,16 +3,46 @@ use axum::{
     response::{IntoResponse, Response},
 };
 
+use super::{git, journal, page};
+
 #[derive(Debug)]
-pub struct AppError(anyhow::Error);
+pub enum AppError {
+    /// Nothing exists at the given path.
+    NotFound(String),
+    Forbidden(String),
+    BadRequest(String),
+    Conflict(String),
+    Internal(anyhow::Error),
+}
+
+/// Message of an error response, rendered into a full page by
+/// `routes::render_error`.
+#[derive(Clone, Debug)]
+pub struct ErrorInfo {
+    pub message: String,
+    /// Path of the missing page or file, for not found errors.
+    pub missing: Option<String>,
+}
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.0),
-        )
-            .into_response()
+        let (status, message, missing) = match self {
+            AppError::NotFound(path) => {
+                (StatusCode::NOT_FOUND, format!("`{path}` doesn't exist"), Some(path))
+            }
+            AppError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg, None),
+            AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg, None),
+            AppError::Conflict(msg) => (StatusCode::CONFLICT, msg, None),
+            AppError::Internal(e) => {
+                tracing::error!("{e:#}");
+                // The details are for the logs, they can contain paths of the server
+                let message = "Something went wrong, the error was logged".to_owned();
+                (StatusCode::INTERNAL_SERVER_ERROR, message, None)
+            }
+        };
+        let mut response = (status, message.clone()).into_response();
+        response.extensions_mut().insert(ErrorInfo { message, missing });
+        response
     }
 }
 impl<E> From<E> for AppError
@@ -20,6 +50,15 @@ where
     E: Into<anyhow::Error>,
 {
     fn from(value: E) -> Self {
-        Self(value.into())
+        let e = value.into();
+        if let Some(git::NotFound(path)) = e.downcast_ref() {
+            AppError::NotFound(path.clone())
+        } else if e.is::<page::ValidationError>() || e.is::<journal::InvalidMonth>() {
+            AppError::BadRequest(e.to_string())
+        } else if e.is::<page::PageExists>() {
+            AppError::Conflict(e.to_string())
+        } else {
+            AppError::Internal(e)
+        }
     }
 }
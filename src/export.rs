@@ -0,0 +1,189 @@
+use include_dir::Dir;
+use minijinja::{context, Environment};
+use std::path::Path;
+
+use crate::git::Repo;
+use crate::page::{get_page, list_files, list_tags, PageEntry};
+use crate::{routes, scss2css, CSS, STATIC_ASSETS};
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+/// File of the exported site serving the wiki URL `url`, if it has one.
+fn local_path(url: &str) -> Option<String> {
+    let path = url.strip_prefix('/')?;
+    Some(match path {
+        "" => "index.html".to_owned(),
+        "page" | "page/" => "page/index.html".to_owned(),
+        "all" | "tags" => format!("{path}.html"),
+        p if p.starts_with("page/") && p.ends_with('/') => format!("{p}index.html"),
+        p if p.starts_with("page/") || p.starts_with("tags/") => format!("{p}.html"),
+        p if p.starts_with("static/") => p.to_owned(),
+        _ => return None,
+    })
+}
+
+/// Whether `tag` can be the name of a file in the `tags` directory. Links
+/// to it are the URL-encoded tag, which is decoded back to the file name but
+/// keeps slashes and dots as they are.
+fn safe_tag(tag: &str) -> bool {
+    !tag.is_empty() && tag != "." && tag != ".." && !tag.contains(['/', '\\'])
+}
+
+/// Absolute form of the link `href` found in the page at `url`.
+pub fn resolve(url: &str, href: &str) -> String {
+    if href.starts_with('/') {
+        return href.to_owned();
+    }
+    let mut segments: Vec<_> = url.split('/').collect();
+    segments.pop();
+    for s in href.split('/') {
+        match s {
+            "." => {}
+            ".." if segments.len() > 1 => {
+                segments.pop();
+            }
+            ".." => {}
+            s => segments.push(s),
+        }
+    }
+    segments.join("/")
+}
+
+/// Make the links of the page at `url` point to the exported files, relative
+/// to the file of the page.
+fn rewrite_links(html: &str, url: &str) -> String {
+    let depth = local_path(url).unwrap_or_default().matches('/').count();
+    let up = "../".repeat(depth);
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some((start, attr)) = [" href=\"", " src=\""]
+        .into_iter()
+        .filter_map(|a| Some((rest.find(a)?, a)))
+        .min()
+    {
+        let value = start + attr.len();
+        let Some(len) = rest[value..].find('"') else {
+            break;
+        };
+        out.push_str(&rest[..value]);
+        let href = rest[value..value + len].replace("&#x2f;", "/");
+        let (path, suffix) = href.split_at(href.find(['#', '?']).unwrap_or(href.len()));
+        let external = path.is_empty() || path.starts_with("//") || path.contains(':');
+        match local_path(&resolve(url, path)).filter(|_| !external) {
+            Some(local) => out.push_str(&format!("{up}{local}{suffix}")),
+            None => out.push_str(&rest[value..value + len]),
+        }
+        rest = &rest[value + len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn write(out: &Path, url: &str, content: &[u8]) -> Result<()> {
+    let path = out.join(local_path(url).expect("not an exported url"));
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    std::fs::write(path, content)?;
+    Ok(())
+}
+
+fn write_html(out: &Path, url: &str, html: &str) -> Result<()> {
+    write(out, url, rewrite_links(html, url).as_bytes())
+}
+
+fn write_assets(out: &Path, dir: &Dir) -> Result<()> {
+    for f in dir.files() {
+        write(out, &format!("/static/{}", f.path().display()), f.contents())?;
+    }
+    for d in dir.dirs() {
+        write_assets(out, d)?;
+    }
+    Ok(())
+}
+
+/// Write every page of the wiki as a static HTML site in `out`. Private
+/// pages are left out unless `private` is set.
+pub fn export(repo: &Repo, env: &Environment, out: &Path, private: bool) -> Result<()> {
+    let visible = |e: &PageEntry| private || !e.meta.private;
+    let mut pages = list_files(repo, "", true)?;
+    pages.retain(visible);
+    let links = std::iter::once(String::new()).chain(pages.iter().map(|e| e.link.clone()));
+    for link in links {
+        let (md, directory) = get_page(repo, &link)?;
+        if !private && md.meta.private {
+            continue;
+        }
+        let children = if directory {
+            let mut children = list_files(repo, &link, false)?;
+            children.retain(visible);
+            Some(children)
+        } else {
+            None
+        };
+        let html = routes::render_page(env, repo, None, &link, md, children)?;
+        write_html(out, &format!("/page/{link}"), &html)?;
+    }
+
+    let templ = env.get_template("pages.html")?;
+    write_html(out, "/all", &templ.render(context!(pages))?)?;
+    let mut tags = list_tags(repo)?;
+    for pages in tags.values_mut() {
+        pages.retain(visible);
+    }
+    tags.retain(|tag, pages| {
+        if pages.is_empty() {
+            return false;
+        }
+        if !safe_tag(tag) {
+            tracing::warn!("tag `{tag}` can't be a file name, left out");
+        }
+        safe_tag(tag)
+    });
+    let templ = env.get_template("tags.html")?;
+    let counts: Vec<_> = tags.iter().map(|(tag, pages)| (tag, pages.len())).collect();
+    write_html(out, "/tags", &templ.render(context!(tags => counts))?)?;
+    let templ = env.get_template("tag.html")?;
+    for (tag, pages) in &tags {
+        let html = templ.render(context!(tag, pages))?;
+        write_html(out, &format!("/tags/{tag}"), &html)?;
+    }
+
+    write(out, "/static/wiki.css", scss2css::site_css(repo, &CSS)?.as_bytes())?;
+    write_assets(out, &STATIC_ASSETS)?;
+    let home = "<meta http-equiv=\"refresh\" content=\"0; url=page/index.html\">\n";
+    write(out, "/", home.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_links() {
+        let html = concat!(
+            "<link href=\"/static/wiki.css\"><a href=\"/page/a&#x2f;b#x\">",
+            "<a href=\"c\"><a href=\"../\"><a href=\"#top\"><a href=\"/edit?page=a\">",
+            "<a href=\"https://example.com/page/\">"
+        );
+        assert_eq!(
+            rewrite_links(html, "/page/a/b"),
+            concat!(
+                "<link href=\"../../static/wiki.css\"><a href=\"../../page/a/b.html#x\">",
+                "<a href=\"../../page/a/c.html\"><a href=\"../../page/index.html\">",
+                "<a href=\"#top\"><a href=\"/edit?page=a\">",
+                "<a href=\"https://example.com/page/\">"
+            )
+        );
+        assert_eq!(local_path("/page/a/"), Some("page/a/index.html".to_owned()));
+    }
+
+    #[test]
+    fn tag_file_names() {
+        assert!(safe_tag("ops"));
+        assert!(safe_tag("a b.c"));
+        assert!(!safe_tag("../../tmp/escaped"));
+        assert!(!safe_tag(".."));
+        assert!(!safe_tag("a\\b"));
+        assert!(!safe_tag(""));
+    }
+}
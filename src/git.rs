@@ -2,9 +2,21 @@ use chrono::{FixedOffset, TimeZone};
 use gix::{actor::Signature, create, object, Repository, ThreadSafeRepository, Tree};
 use serde_derive::{Deserialize, Serialize};
 use std::path::Path;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::metrics;
 
 type Result<T> = std::result::Result<T, anyhow::Error>;
 
+/// Held while a commit is written, from reading `master` to updating it.
+static COMMIT_LOCK: Mutex<()> = Mutex::new(());
+
+/// Wait for the commit being written, if any, and keep new ones from
+/// starting until the guard is dropped.
+pub fn block_commits() -> MutexGuard<'static, ()> {
+    COMMIT_LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 #[derive(Clone)]
 pub struct ThreadSafeRepo {
     repo: ThreadSafeRepository,
@@ -29,8 +41,23 @@ pub struct CommitData {
     pub author: String,
     pub added: Vec<(String, String)>,
     pub removed: Vec<String>,
+    /// Seconds since the epoch, for commits that didn't happen now.
+    #[serde(default)]
+    pub timestamp: Option<i64>,
 }
 
+/// Nothing exists at the given path on `master`.
+#[derive(Debug)]
+pub struct NotFound(pub String);
+
+impl std::fmt::Display for NotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "`{}` not found", self.0)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub enum EntryKind {
     File,
@@ -47,7 +74,11 @@ pub struct Entry {
 impl ThreadSafeRepo {
     pub fn open(path: &str) -> Result<ThreadSafeRepo> {
         let repo = ThreadSafeRepository::open(path).or_else(|_| {
-            ThreadSafeRepository::init(path, create::Kind::Bare, create::Options::default())
+            // Everything happens on `master`, HEAD should point to it
+            let open =
+                gix::open::Options::default().config_overrides(["init.defaultBranch=master"]);
+            ThreadSafeRepository::init_opts(path, create::Kind::Bare, create::Options::default(), open)
+                .map_err(anyhow::Error::from)
         })?;
         Ok(ThreadSafeRepo { repo })
     }
@@ -59,31 +90,42 @@ impl ThreadSafeRepo {
 }
 
 impl Repo {
-    fn get_blob(&self, path: &str) -> Result<Vec<u8>> {
+    pub fn get_blob(&self, path: &str) -> Result<Vec<u8>> {
+        let _timer = metrics::time_git("get_blob");
         let id = self
             .repo
-            .rev_parse_single(format!("master:{}", path).as_bytes())?;
+            .rev_parse_single(format!("master:{}", path).as_bytes())
+            .map_err(|_| NotFound(path.to_owned()))?;
         let obj = id.object()?;
         let blob = obj.peel_to_kind(object::Kind::Blob)?;
         Ok(blob.data.clone())
     }
 
+    /// Commit `master` points to.
+    pub fn head_id(&self) -> Result<gix::ObjectId> {
+        Ok(self.repo.rev_parse_single("master")?.detach())
+    }
+
     pub fn get_blob_from_id(&self, id: gix::ObjectId) -> Result<Vec<u8>> {
+        let _timer = metrics::time_git("get_blob");
         let obj = self.repo.try_find_object(id)?.unwrap();
         let blob = obj.peel_to_kind(object::Kind::Blob)?;
         Ok(blob.data.clone())
     }
 
     pub fn get_tree<'a>(&'a self, path: &str) -> Result<Tree<'a>> {
+        let _timer = metrics::time_git("get_tree");
         let id = self
             .repo
-            .rev_parse_single(format!("master:{}", path).as_bytes())?;
+            .rev_parse_single(format!("master:{}", path).as_bytes())
+            .map_err(|_| NotFound(path.to_owned()))?;
         let obj = id.object()?;
         let tree = obj.peel_to_kind(object::Kind::Tree)?.into_tree();
         Ok(tree)
     }
 
     pub fn get_tree_from_id(&self, id: gix::ObjectId) -> Result<gix::Tree<'_>> {
+        let _timer = metrics::time_git("get_tree");
         let obj = self.repo.try_find_object(id)?.unwrap();
         let tree = obj.peel_to_kind(object::Kind::Tree)?.into_tree();
         Ok(tree)
@@ -115,7 +157,10 @@ impl Repo {
     }
 
     pub fn commit(&self, data: &CommitData) -> Result<gix::ObjectId> {
-        let tree = self.get_tree("")?;
+        let _lock = block_commits();
+        let _timer = metrics::time_git("commit");
+        // An empty repo has no `master` yet
+        let tree = self.get_tree("").unwrap_or_else(|_| self.repo.empty_tree());
         let mut treebuilder = TreeUpdateBuilder::new();
 
         for (path, content) in &data.added {
@@ -126,13 +171,25 @@ impl Repo {
         let oid = treebuilder.create_updated(&self.repo, &tree);
         let newtree = self.repo.find_object(oid).unwrap();
 
+        // Authors can be given as `Name <email>`
+        let (name, email) = match data.author.split_once(" <") {
+            Some((name, email)) if email.ends_with('>') => {
+                (name.to_owned(), email.trim_end_matches('>').to_owned())
+            }
+            _ => (data.author.clone(), format!("{}@peori.space", &data.author)),
+        };
         let sig = Signature {
-            name: data.author.clone().into(),
-            email: format!("{}@peori.space", &data.author).into(),
-            time: gix::date::Time::now_local_or_utc(),
+            name: name.into(),
+            email: email.into(),
+            time: data
+                .timestamp
+                .map(|t| gix::date::Time::new(t, 0))
+                .unwrap_or_else(gix::date::Time::now_local_or_utc),
+        };
+        let parent = match self.repo.try_find_reference("refs/heads/master")? {
+            Some(mut branch) => Some(branch.peel_to_id()?),
+            None => None,
         };
-        let mut branch = self.repo.find_reference("master")?;
-        let parent = branch.peel_to_id()?;
         let mut committer_buf = gix::date::parse::TimeBuf::default();
         let mut author_buf = gix::date::parse::TimeBuf::default();
         Ok(self
@@ -140,17 +197,18 @@ impl Repo {
             .commit_as(
                 sig.to_ref(&mut committer_buf),
                 sig.to_ref(&mut author_buf),
-                branch.name().as_bstr(),
+                "refs/heads/master",
                 &data.msg,
                 newtree.id,
-                Some(parent),
+                parent,
             )
             .unwrap()
             .into())
     }
 
     pub fn get_log(&self) -> Result<Vec<CommitLog>> {
-        let head = self.repo.head_id()?.object()?.id;
+        let _timer = metrics::time_git("get_log");
+        let head = self.head_id()?;
         let walk = self.repo.rev_walk(Some(head));
         let mut ret = Vec::new();
         for info in walk.all()? {
@@ -0,0 +1,447 @@
+use slug::slugify;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Component, Path, PathBuf};
+
+use crate::check::decode;
+use crate::git::{CommitData, Repo};
+use crate::page::{has_front_matter, read_page, write_page, Metadata, RawPage, REDIRECTS_FILE};
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+/// Files used as the page of the directory they are in.
+const INDEX_FILES: [&str; 4] = ["_index.md", "index.md", "readme.md", "readme.markdown"];
+
+fn is_markdown(name: &str) -> bool {
+    name.ends_with(".md") || name.ends_with(".markdown")
+}
+
+/// Content of an empty directory page.
+pub fn dir_page(title: String) -> Result<String> {
+    let page = RawPage {
+        meta: Metadata {
+            title,
+            ..Default::default()
+        },
+        content: String::new(),
+        error: None,
+    };
+    write_page(&page, None)
+}
+
+/// Page content for the file `name`. Files without front matter get one
+/// with their title, the others are kept as they are.
+fn convert(content: &str, name: &str) -> Result<String> {
+    let page = read_page(content, name);
+    if let Some(e) = &page.error {
+        tracing::warn!("{name}: {e}, imported as it is");
+        return Ok(content.to_owned());
+    }
+    if has_front_matter(content) {
+        return Ok(content.to_owned());
+    }
+    write_page(&page, None)
+}
+
+/// Pages for the markdown files in `dir`, keyed by path in the wiki, and
+/// the files they come from. Returns the number of files that were left out.
+fn walk(
+    dir: &Path,
+    prefix: &str,
+    pages: &mut BTreeMap<String, String>,
+    sources: &mut BTreeMap<String, PathBuf>,
+) -> Result<usize> {
+    let mut entries: Vec<_> = std::fs::read_dir(dir)?.collect::<std::io::Result<_>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    let mut skipped = 0;
+    let mut index = None;
+    for e in entries {
+        let name = e.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        if e.file_type()?.is_dir() {
+            let sub = format!("{prefix}{}/", slugify(&name));
+            skipped += walk(&e.path(), &sub, pages, sources)?;
+            let sub_index = format!("{sub}_index.md");
+            // Directories without markdown files are left out
+            let has_pages = pages.keys().any(|k| k.starts_with(&sub));
+            if has_pages && !pages.contains_key(&sub_index) {
+                pages.insert(sub_index, dir_page(name)?);
+            }
+        } else if !is_markdown(&name) {
+            tracing::warn!("{}: not a markdown file, skipped", e.path().display());
+            skipped += 1;
+        } else {
+            let stem = name
+                .rsplit_once('.')
+                .map_or(name.as_str(), |(stem, _)| stem);
+            let content = std::fs::read_to_string(e.path())?;
+            if INDEX_FILES.contains(&name.to_lowercase().as_str()) && index.is_none() {
+                index = Some(convert(&content, stem)?);
+                sources.insert(format!("{prefix}_index.md"), e.path());
+                continue;
+            }
+            let path = format!("{prefix}{}.md", slugify(stem));
+            sources.insert(path.clone(), e.path());
+            if pages
+                .insert(path.clone(), convert(&content, stem)?)
+                .is_some()
+            {
+                anyhow::bail!(
+                    "{}: more than one file would be imported as `{path}`",
+                    dir.display()
+                );
+            }
+        }
+    }
+    if let Some(index) = index {
+        pages.insert(format!("{prefix}_index.md"), index);
+    }
+    Ok(skipped)
+}
+
+/// `path` without `.` and `..` components.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Link to the page stored at `path` in the wiki.
+fn page_link(path: &str) -> String {
+    let path = path.strip_suffix(".md").unwrap_or(path);
+    format!("/page/{}", path.strip_suffix("_index").unwrap_or(path))
+}
+
+/// Make the relative links of the page imported from `source` to other
+/// imported files point to their pages. `links` maps the imported files to
+/// the links of their pages.
+fn rewrite_links(content: &str, source: &Path, links: &BTreeMap<PathBuf, String>) -> String {
+    let dir = source.parent().unwrap_or(Path::new(""));
+    let mut out = String::with_capacity(content.len());
+    let mut fence: Option<&str> = None;
+    for line in content.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if let Some(f) = fence {
+            if trimmed.starts_with(f) {
+                fence = None;
+            }
+            out.push_str(line);
+            continue;
+        } else if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            fence = Some(&trimmed[..3]);
+            out.push_str(line);
+            continue;
+        }
+        let mut rest = line;
+        while let Some(start) = rest.find("](") {
+            let target = &rest[start + 2..];
+            let Some(len) = target.find(')') else {
+                break;
+            };
+            out.push_str(&rest[..start + 2]);
+            let href = &target[..len];
+            let (path, anchor) = href.split_at(href.find('#').unwrap_or(href.len()));
+            let path = path.trim_start_matches('<').trim_end_matches('>');
+            let external = path.is_empty() || path.starts_with('/') || path.contains(':');
+            match links.get(&normalize(&dir.join(decode(path)))) {
+                Some(link) if !external => out.push_str(&format!("{link}{anchor}")),
+                _ => out.push_str(href),
+            }
+            rest = &target[len..];
+        }
+        out.push_str(rest);
+    }
+    out
+}
+
+/// Directory pages of `into` and the directories above it, with their
+/// titles.
+pub fn dir_indexes(into: &str) -> Vec<(String, String)> {
+    let mut indexes = vec![("_index.md".to_owned(), "Home".to_owned())];
+    let mut dir = String::new();
+    for name in into.split('/').filter(|n| !n.is_empty()) {
+        dir.push_str(name);
+        dir.push('/');
+        indexes.push((format!("{dir}_index.md"), name.to_owned()));
+    }
+    indexes
+}
+
+/// Path prefix of the pages imported into the wiki directory `into`.
+pub fn base_path(into: &str) -> String {
+    let mut prefix = into.trim_matches('/').to_owned();
+    if !prefix.is_empty() {
+        prefix.push('/');
+    }
+    prefix
+}
+
+/// Fail if some of the files at `paths` exist in the wiki, unless `force`
+/// is set to replace them.
+fn check_existing<'a>(
+    repo: &Repo,
+    paths: impl Iterator<Item = &'a String>,
+    force: bool,
+) -> Result<()> {
+    if force {
+        return Ok(());
+    }
+    let existing: Vec<_> = paths
+        .filter(|p| repo.get_blob(p).is_ok())
+        .map(|p| p.as_str())
+        .collect();
+    if !existing.is_empty() {
+        anyhow::bail!(
+            "{} files already exist in the wiki, use --force to replace them: {}",
+            existing.len(),
+            existing.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Import the markdown files in `dir` into the wiki directory `into`, as a
+/// single commit. Subdirectories become directory pages. Existing pages are
+/// only replaced if `force` is set.
+pub fn import(repo: &Repo, dir: &Path, into: &str, author: &str, force: bool) -> Result<()> {
+    let prefix = base_path(into);
+    let mut pages = BTreeMap::new();
+    let mut sources = BTreeMap::new();
+    // Links are resolved against the paths of the files
+    let skipped = walk(&dir.canonicalize()?, &prefix, &mut pages, &mut sources)?;
+    check_existing(repo, pages.keys(), force)?;
+    let links: BTreeMap<_, _> = sources
+        .iter()
+        .map(|(path, source)| (normalize(source), page_link(path)))
+        .collect();
+    for (path, source) in &sources {
+        if let Some(content) = pages.get_mut(path) {
+            *content = rewrite_links(content, &normalize(source), &links);
+        }
+    }
+    // The directories the pages go in get a page too, unless they have one
+    for (index, title) in dir_indexes(into) {
+        if !pages.contains_key(&index) && repo.get_file(&index).is_err() {
+            pages.insert(index, dir_page(title)?);
+        }
+    }
+    if pages.is_empty() {
+        anyhow::bail!("no markdown files in {}", dir.display());
+    }
+    let count = pages.len();
+    repo.commit(&CommitData {
+        msg: format!("Imported {count} pages from `{}`", dir.display()),
+        author: author.to_owned(),
+        added: pages.into_iter().collect(),
+        removed: vec![],
+        timestamp: None,
+    })?;
+    println!("Imported {count} pages, skipped {skipped} files");
+    Ok(())
+}
+
+/// A revision of a page from another wiki, converted to markdown.
+pub struct Revision {
+    /// Path of the page in the wiki.
+    pub link: String,
+    pub title: String,
+    pub tags: Vec<String>,
+    pub content: String,
+    pub author: String,
+    /// Seconds since the epoch.
+    pub timestamp: i64,
+    pub comment: String,
+}
+
+/// Pages and redirects read from another wiki.
+#[derive(Default)]
+pub struct History {
+    pub revisions: Vec<Revision>,
+    /// Paths of redirect pages and the page they point to.
+    pub redirects: BTreeMap<String, String>,
+}
+
+/// Commit the `history` of another wiki, one commit per revision with its
+/// original author and date. Directories get a page when they first appear.
+/// Existing pages are only replaced if `force` is set.
+pub fn replay(repo: &Repo, mut history: History, author: &str, force: bool) -> Result<()> {
+    let files: BTreeSet<_> = history
+        .revisions
+        .iter()
+        .map(|r| format!("{}.md", r.link))
+        .collect();
+    check_existing(repo, files.iter(), force)?;
+    history.revisions.sort_by_key(|r| r.timestamp);
+    let count = history.revisions.len();
+    let mut dirs = BTreeSet::new();
+    for rev in history.revisions {
+        let mut added = vec![];
+        let mut parents: Vec<_> = rev
+            .link
+            .match_indices('/')
+            .map(|(i, _)| &rev.link[..i])
+            .collect();
+        parents.insert(0, "");
+        for dir in parents {
+            let index = if dir.is_empty() {
+                "_index.md".to_owned()
+            } else {
+                format!("{dir}/_index.md")
+            };
+            if dirs.insert(dir.to_owned()) && repo.get_file(&index).is_err() {
+                let title = match dir.rsplit('/').next() {
+                    Some("") | None => "Home",
+                    Some(name) => name,
+                };
+                added.push((index, dir_page(title.to_owned())?));
+            }
+        }
+        let msg = if rev.comment.trim().is_empty() {
+            format!("Edited `{}`", rev.title)
+        } else {
+            rev.comment
+        };
+        let page = RawPage {
+            meta: Metadata {
+                title: rev.title,
+                tags: rev.tags,
+                ..Default::default()
+            },
+            content: rev.content,
+            error: None,
+        };
+        added.push((format!("{}.md", rev.link), write_page(&page, None)?));
+        repo.commit(&CommitData {
+            msg,
+            author: rev.author,
+            added,
+            removed: vec![],
+            timestamp: Some(rev.timestamp),
+        })?;
+    }
+    if !history.redirects.is_empty() {
+        let mut redirects: BTreeMap<String, String> = match repo.get_file(REDIRECTS_FILE) {
+            Ok(content) => serde_yaml::from_str(&content)?,
+            Err(_) => BTreeMap::new(),
+        };
+        redirects.extend(history.redirects);
+        repo.commit(&CommitData {
+            msg: "Imported redirects".to_owned(),
+            author: author.to_owned(),
+            added: vec![(
+                REDIRECTS_FILE.to_owned(),
+                serde_yaml::to_string(&redirects)?,
+            )],
+            removed: vec![],
+            timestamp: None,
+        })?;
+    }
+    println!("Imported {count} revisions");
+    Ok(())
+}
+
+/// A GFM table, with the first row as header.
+pub fn markdown_table(rows: &[Vec<String>]) -> String {
+    let width = rows.iter().map(Vec::len).max().unwrap_or_default();
+    if width == 0 {
+        return String::new();
+    }
+    let mut out = String::new();
+    for (i, row) in rows.iter().enumerate() {
+        out.push('|');
+        for c in 0..width {
+            let cell = row.get(c).map(|c| c.trim().replace('|', "\\|"));
+            out.push_str(&format!(" {} |", cell.unwrap_or_default()));
+        }
+        out.push('\n');
+        if i == 0 {
+            out.push_str(&"| --- ".repeat(width));
+            out.push_str("|\n");
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn import_tree() {
+        let dir = std::env::temp_dir().join(format!("wikimark-import-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("Team Notes/empty")).unwrap();
+        std::fs::write(dir.join("README.md"), "# Docs\nWelcome\n").unwrap();
+        std::fs::write(dir.join("Team Notes/On Call.md"), "Rotation\n").unwrap();
+        std::fs::write(dir.join("Team Notes/kept.md"), "---\nowner: me\n---\nx\n").unwrap();
+        std::fs::write(dir.join("logo.png"), "").unwrap();
+        let mut pages = BTreeMap::new();
+        assert_eq!(walk(&dir, "", &mut pages, &mut BTreeMap::new()).unwrap(), 1);
+        assert_eq!(
+            pages.keys().collect::<Vec<_>>(),
+            [
+                "_index.md",
+                "team-notes/_index.md",
+                "team-notes/kept.md",
+                "team-notes/on-call.md"
+            ]
+        );
+        assert_eq!(
+            pages["_index.md"],
+            "---\ntitle: Docs\nprivate: false\n---\n# Docs\nWelcome\n"
+        );
+        assert_eq!(
+            pages["team-notes/_index.md"],
+            "---\ntitle: Team Notes\nprivate: false\n---\n"
+        );
+        assert_eq!(
+            pages["team-notes/on-call.md"],
+            "---\ntitle: On Call\nprivate: false\n---\nRotation\n"
+        );
+        assert_eq!(pages["team-notes/kept.md"], "---\nowner: me\n---\nx\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn links_between_pages() {
+        let links = BTreeMap::from([
+            (
+                PathBuf::from("/src/My Page.md"),
+                "/page/notes/my-page".to_owned(),
+            ),
+            (PathBuf::from("/src/README.md"), "/page/notes/".to_owned()),
+        ]);
+        let content = concat!(
+            "[x](My Page.md) [y](<My Page.md>#setup) [z](../src/My%20Page.md)\n",
+            "[home](README.md) [ext](https://a.b/My Page.md) [other](Other.md)\n",
+            "```\n[x](My Page.md)\n```\n",
+        );
+        assert_eq!(
+            rewrite_links(content, Path::new("/src/sub/../README.md"), &links),
+            concat!(
+                "[x](/page/notes/my-page) [y](/page/notes/my-page#setup) ",
+                "[z](/page/notes/my-page)\n",
+                "[home](/page/notes/) [ext](https://a.b/My Page.md) [other](Other.md)\n",
+                "```\n[x](My Page.md)\n```\n",
+            )
+        );
+        assert_eq!(page_link("a/_index.md"), "/page/a/");
+        assert_eq!(page_link("_index.md"), "/page/");
+    }
+
+    #[test]
+    fn parent_indexes() {
+        let indexes: Vec<_> = dir_indexes("a/b/").into_iter().map(|(i, _)| i).collect();
+        assert_eq!(indexes, ["_index.md", "a/_index.md", "a/b/_index.md"]);
+        assert_eq!(dir_indexes("a")[1].1, "a");
+        assert_eq!(dir_indexes("").len(), 1);
+    }
+}
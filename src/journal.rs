@@ -0,0 +1,178 @@
+use chrono::{DateTime, Datelike, Months, NaiveDate};
+use serde_derive::Serialize;
+use slug::slugify;
+use std::collections::BTreeMap;
+
+use crate::git::{CommitLog, Repo};
+use crate::import::{dir_indexes, dir_page};
+use crate::page::{list_files, PageEntry};
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+/// Directory of the journal of `user`. The configured directory can contain
+/// a `{user}` placeholder to give everyone their own journal.
+pub fn journal_dir(pattern: &str, user: &str) -> String {
+    pattern
+        .replace("{user}", &slugify(user))
+        .trim_matches('/')
+        .to_owned()
+}
+
+/// Link to the journal entry for `date`.
+pub fn entry_link(dir: &str, date: NaiveDate) -> String {
+    let date = date.format("%Y-%m-%d");
+    if dir.is_empty() {
+        date.to_string()
+    } else {
+        format!("{dir}/{date}")
+    }
+}
+
+/// A month that doesn't exist, or whose calendar can't be shown.
+#[derive(Debug)]
+pub struct InvalidMonth(pub i32, pub u32);
+
+impl std::fmt::Display for InvalidMonth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid month {}/{:02}", self.0, self.1)
+    }
+}
+
+impl std::error::Error for InvalidMonth {}
+
+/// Pages of the journal directory `dir` and the directories above it that
+/// don't exist yet, to add along with the first entry.
+pub fn missing_dir_pages(repo: &Repo, dir: &str) -> Result<Vec<(String, String)>> {
+    dir_indexes(dir)
+        .into_iter()
+        .filter(|(index, _)| repo.get_file(index).is_err())
+        .map(|(index, title)| Ok((index, dir_page(title)?)))
+        .collect()
+}
+
+#[derive(Serialize, Debug)]
+pub struct Day {
+    pub day: u32,
+    pub entry: Option<PageEntry>,
+    /// Commits made to the wiki on that day.
+    pub commits: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Month {
+    pub title: String,
+    pub prev: String,
+    pub next: String,
+    /// Weeks starting on Monday, padded with `None` outside the month.
+    pub weeks: Vec<Vec<Option<Day>>>,
+}
+
+fn calendar(
+    first: NaiveDate,
+    mut entries: BTreeMap<NaiveDate, PageEntry>,
+    commits: &BTreeMap<NaiveDate, usize>,
+) -> Vec<Vec<Option<Day>>> {
+    let mut weeks = vec![];
+    let mut week: Vec<_> = (0..first.weekday().num_days_from_monday())
+        .map(|_| None)
+        .collect();
+    for date in first.iter_days().take_while(|d| d.month() == first.month()) {
+        week.push(Some(Day {
+            day: date.day(),
+            entry: entries.remove(&date),
+            commits: commits.get(&date).copied().unwrap_or_default(),
+        }));
+        if week.len() == 7 {
+            weeks.push(std::mem::take(&mut week));
+        }
+    }
+    if !week.is_empty() {
+        week.resize_with(7, || None);
+        weeks.push(week);
+    }
+    weeks
+}
+
+/// Calendar of the entries in the journal at `dir` for the given month.
+pub fn month(repo: &Repo, dir: &str, year: i32, month: u32) -> Result<Month> {
+    let invalid = || InvalidMonth(year, month);
+    let first = NaiveDate::from_ymd_opt(year, month, 1).ok_or_else(invalid)?;
+    // The calendar links to the months around it
+    let prev = first.checked_sub_months(Months::new(1)).ok_or_else(invalid)?;
+    let next = first.checked_add_months(Months::new(1)).ok_or_else(invalid)?;
+    let path = if dir.is_empty() { String::new() } else { format!("{dir}/") };
+    // The journal directory only exists after the first entry
+    let entries = if repo.get_tree(&path).is_ok() {
+        list_files(repo, &path, false)?
+    } else {
+        vec![]
+    };
+    let entries = entries
+        .into_iter()
+        .filter_map(|e| {
+            let name = e.link.rsplit('/').next()?;
+            let date = NaiveDate::parse_from_str(name, "%Y-%m-%d").ok()?;
+            Some((date, e))
+        })
+        .collect();
+    let mut commits = BTreeMap::new();
+    for CommitLog { date, .. } in repo.get_log()? {
+        if let Ok(date) = DateTime::parse_from_rfc2822(&date) {
+            *commits.entry(date.date_naive()).or_default() += 1;
+        }
+    }
+    Ok(Month {
+        title: first.format("%B %Y").to_string(),
+        prev: prev.format("%Y/%m").to_string(),
+        next: next.format("%Y/%m").to_string(),
+        weeks: calendar(first, entries, &commits),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn month_grid() {
+        // October 2026 starts on a Thursday
+        let first = NaiveDate::from_ymd_opt(2026, 10, 1).unwrap();
+        let commits = BTreeMap::from([(NaiveDate::from_ymd_opt(2026, 10, 18).unwrap(), 2)]);
+        let weeks = calendar(first, BTreeMap::new(), &commits);
+        assert_eq!(weeks.len(), 5);
+        assert!(weeks.iter().all(|w| w.len() == 7));
+        assert!(weeks[0][2].is_none());
+        assert_eq!(weeks[0][3].as_ref().unwrap().day, 1);
+        assert_eq!(weeks[2][6].as_ref().unwrap().commits, 2);
+        assert_eq!(weeks[4][5].as_ref().unwrap().day, 31);
+        assert!(weeks[4][6].is_none());
+    }
+
+    #[test]
+    fn dir_pages_of_first_entry() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let repo = crate::git::ThreadSafeRepo::open(tmp.path().to_str().unwrap())
+            .unwrap()
+            .local();
+        let added = missing_dir_pages(&repo, "journal/ann").unwrap();
+        let paths: Vec<_> = added.iter().map(|(p, _)| p.as_str()).collect();
+        assert_eq!(paths, ["_index.md", "journal/_index.md", "journal/ann/_index.md"]);
+        assert_eq!(added[2].1, "---\ntitle: ann\nprivate: false\n---\n");
+        repo.commit(&crate::git::CommitData {
+            msg: "Journal".to_owned(),
+            author: "ann".to_owned(),
+            added,
+            removed: vec![],
+            timestamp: None,
+        })
+        .unwrap();
+        assert!(missing_dir_pages(&repo, "journal/ann").unwrap().is_empty());
+    }
+
+    #[test]
+    fn per_user_dir() {
+        assert_eq!(journal_dir("journal/{user}/", "Jane Doe"), "journal/jane-doe");
+        let date = NaiveDate::from_ymd_opt(2026, 10, 18).unwrap();
+        assert_eq!(entry_link("", date), "2026-10-18");
+    }
+}
@@ -0,0 +1,201 @@
+use axum::extract::Request;
+use axum::middleware::{self, Next};
+use axum::response::Response;
+use axum::Router;
+use std::fs::DirBuilder;
+use std::os::fd::{FromRawFd, IntoRawFd};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::net::{TcpListener, UnixListener};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+use crate::config::Config;
+use crate::git;
+use crate::tls::{self, TlsListener};
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+/// First file descriptor passed by systemd socket activation.
+const SD_LISTEN_FDS_START: i32 = 3;
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Tls(TlsListener),
+    Unix(UnixListener),
+}
+
+/// The socket passed by systemd, if the process was socket activated.
+fn systemd_listener() -> Result<Option<Listener>> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS");
+    // Child processes must not take the socket for theirs
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        // SAFETY: called at startup, before any other thread uses the
+        // environment
+        unsafe { std::env::remove_var(var) };
+    }
+    if pid.and_then(|p| p.parse().ok()) != Some(std::process::id()) {
+        return Ok(None);
+    }
+    let fds: i32 = fds?.parse()?;
+    if fds != 1 {
+        anyhow::bail!("expected one socket from systemd, got {fds}");
+    }
+    // SAFETY: systemd passes us this descriptor and nothing else owns it
+    let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(SD_LISTEN_FDS_START) };
+    // Only Unix sockets have a Unix socket address
+    if unix.local_addr().is_ok() {
+        unix.set_nonblocking(true)?;
+        return Ok(Some(Listener::Unix(UnixListener::from_std(unix)?)));
+    }
+    // SAFETY: the descriptor was just released by `unix`
+    let tcp = unsafe { std::net::TcpListener::from_raw_fd(unix.into_raw_fd()) };
+    tcp.set_nonblocking(true)?;
+    Ok(Some(Listener::Tcp(TcpListener::from_std(tcp)?)))
+}
+
+/// Listen on the socket passed by systemd, the Unix socket of `config` or
+/// its address and port, in this order. TCP sockets serve HTTPS if `config`
+/// has a certificate.
+pub async fn bind(config: &Config) -> Result<Listener> {
+    let listener = bind_plain(config).await?;
+    if !config.tls.enabled() {
+        return Ok(listener);
+    }
+    let Listener::Tcp(listener) = listener else {
+        anyhow::bail!("TLS can only be used on TCP sockets");
+    };
+    if !config.tls.redirect_http.is_empty() {
+        tls::redirect_http(&config.tls.redirect_http, listener.local_addr()?.port()).await?;
+    }
+    Ok(Listener::Tls(TlsListener::new(listener, &config.tls)?))
+}
+
+async fn bind_plain(config: &Config) -> Result<Listener> {
+    if let Some(listener) = systemd_listener()? {
+        tracing::info!("listening on the socket passed by systemd");
+        return Ok(listener);
+    }
+    if config.socket.is_empty() {
+        let address = format!("{}:{}", config.address, config.port);
+        tracing::info!("listening on {address}");
+        return Ok(Listener::Tcp(TcpListener::bind(address).await?));
+    }
+    let path = &config.socket;
+    let mode = u32::from_str_radix(&config.socket_mode, 8)
+        .map_err(|_| anyhow::anyhow!("invalid socket mode `{}`", config.socket_mode))?;
+    // A previous run leaves its socket behind
+    if std::fs::metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = bind_private(path, mode)?;
+    tracing::info!("listening on {path}");
+    Ok(Listener::Unix(listener))
+}
+
+/// Bind the Unix socket `path` with the permissions `mode`. The socket is
+/// created in a directory only we can access, so that nobody can connect
+/// before its permissions are set, and then moved to `path`.
+fn bind_private(path: &str, mode: u32) -> Result<UnixListener> {
+    let path = Path::new(path);
+    let mut dir = path.as_os_str().to_owned();
+    dir.push(format!(".{}", std::process::id()));
+    let dir = PathBuf::from(dir);
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    let bind = || {
+        let tmp = dir.join("socket");
+        let listener = UnixListener::bind(&tmp)?;
+        std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&tmp, path)?;
+        Ok(listener)
+    };
+    let listener = bind();
+    let _ = std::fs::remove_dir_all(&dir);
+    listener
+}
+
+/// Resolves on SIGTERM or SIGINT.
+async fn shutdown_signal() -> Result<()> {
+    let mut term = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = term.recv() => {}
+        r = tokio::signal::ctrl_c() => r?,
+    }
+    Ok(())
+}
+
+/// Requests being handled, each holds its connection open.
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+/// Decrements `RUNNING` when the request is done or dropped.
+struct Running;
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+async fn count_running(req: Request, next: Next) -> Response {
+    RUNNING.fetch_add(1, Ordering::Relaxed);
+    let _running = Running;
+    next.run(req).await
+}
+
+/// Serve `app` until SIGTERM or SIGINT, then stop accepting connections and
+/// wait up to `timeout` for the running requests. Returns once no commit is
+/// being written, and none can start anymore, or exits the process with an
+/// error if some requests are still running.
+pub async fn serve(listener: Listener, app: Router, timeout: Duration) -> Result<()> {
+    let app = app.layer(middleware::from_fn(count_running));
+    let (stop, stopped) = watch::channel(false);
+    tokio::spawn(async move {
+        if let Err(e) = shutdown_signal().await {
+            tracing::error!("cannot wait for signals: {e}");
+            return;
+        }
+        tracing::info!("shutting down");
+        let _ = stop.send(true);
+    });
+    let signal = || {
+        let mut stopped = stopped.clone();
+        async move {
+            let _ = stopped.wait_for(|s| *s).await;
+        }
+    };
+    let server = async {
+        match listener {
+            Listener::Tcp(l) => axum::serve(l, app).with_graceful_shutdown(signal()).await,
+            Listener::Tls(l) => axum::serve(l, app).with_graceful_shutdown(signal()).await,
+            Listener::Unix(l) => axum::serve(l, app).with_graceful_shutdown(signal()).await,
+        }
+    };
+    let deadline = async {
+        signal().await;
+        tokio::time::sleep(timeout).await;
+    };
+    let finished = tokio::select! {
+        r = server => {
+            r?;
+            true
+        }
+        _ = deadline => {
+            let running = RUNNING.load(Ordering::Relaxed);
+            tracing::warn!(
+                "{running} connections still running a request after {timeout:?}, dropping them"
+            );
+            false
+        }
+    };
+    // The lock is never released, the process is about to exit
+    tokio::task::spawn_blocking(|| std::mem::forget(git::block_commits())).await?;
+    if !finished {
+        // Requests still running would wait for the lock forever, and the
+        // runtime waits for them when it is dropped
+        std::process::exit(1);
+    }
+    Ok(())
+}
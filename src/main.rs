@@ -1,46 +1,181 @@
 use axum::{
     routing::{get, post},
+    response::Redirect,
     Router,
 };
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use include_dir::{include_dir, Dir};
 use minijinja::Environment;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use tower_http::trace::{self, TraceLayer};
 use tracing::Level;
 
+mod check;
+mod config;
+mod dokuwiki;
 mod errors;
+mod export;
 mod git;
+mod import;
+mod journal;
+mod listen;
 mod md2html;
+mod mediawiki;
+mod metrics;
 mod page;
+mod query;
 mod routes;
+mod scss2css;
+mod tls;
 
 pub static STATIC_ASSETS: Dir = include_dir!("static");
 pub static TEMPLATES: Dir = include_dir!("templates");
-pub static CSS: &str = concat!(
-    include_str!("../css/reset.css"),
-    include_str!("../css/icons.css"),
-    include_str!("../css/wiki.css"),
-    include_str!("../css/content.css"),
-);
+pub static CSS: [(&str, &str); 4] = [
+    ("reset.css", include_str!("../css/reset.css")),
+    ("icons.css", include_str!("../css/icons.css")),
+    ("wiki.css", include_str!("../css/wiki.css")),
+    ("content.css", include_str!("../css/content.css")),
+];
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[arg(short, long, env = "WIKIMARK_PORT", default_value = "3000")]
-    port: u16,
-    #[arg(short, long, env = "WIKIMARK_ADDR", default_value = "127.0.0.1")]
-    address: String,
-    #[arg(short, long, env = "WIKIMARK_REPO", default_value = "repo")]
-    repo: String,
-    #[arg(short, long, env = "WIKIMARK_COMMIT_URL_PREFIX", default_value = "")]
-    commit_url_prefix: String,
+    /// Configuration file, on top of `wikimark.toml` in the wiki repo
+    #[arg(long, env = "WIKIMARK_CONFIG", global = true)]
+    config: Option<PathBuf>,
+    #[arg(short, long, env = "WIKIMARK_PORT")]
+    port: Option<u16>,
+    #[arg(short, long, env = "WIKIMARK_ADDR")]
+    address: Option<String>,
+    /// Unix socket to listen on instead of the address and port
+    #[arg(long, env = "WIKIMARK_SOCKET")]
+    socket: Option<String>,
+    /// Permissions of the Unix socket, in octal
+    #[arg(long, env = "WIKIMARK_SOCKET_MODE")]
+    socket_mode: Option<String>,
+    /// PEM certificate chain to serve HTTPS with
+    #[arg(long, env = "WIKIMARK_TLS_CERT")]
+    tls_cert: Option<String>,
+    /// PEM private key of the certificate
+    #[arg(long, env = "WIKIMARK_TLS_KEY")]
+    tls_key: Option<String>,
+    /// Address to redirect plain HTTP to HTTPS from, like `0.0.0.0:80`
+    #[arg(long, env = "WIKIMARK_TLS_REDIRECT_HTTP")]
+    tls_redirect_http: Option<String>,
+    /// Seconds to wait for running requests when shutting down
+    #[arg(long, env = "WIKIMARK_SHUTDOWN_TIMEOUT")]
+    shutdown_timeout: Option<u64>,
+    #[arg(short, long, env = "WIKIMARK_REPO", global = true)]
+    repo: Option<String>,
+    /// URL path to serve the wiki under, like `/wiki`
+    #[arg(long, env = "WIKIMARK_BASE_PATH")]
+    base_path: Option<String>,
+    #[arg(short, long, env = "WIKIMARK_COMMIT_URL_PREFIX")]
+    commit_url_prefix: Option<String>,
+    /// Directory of journal entries, `{user}` is replaced with the user name
+    #[arg(short, long, env = "WIKIMARK_JOURNAL_DIR")]
+    journal_dir: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+impl Args {
+    /// Replace the settings of `config` given on the command line.
+    fn apply(&self, config: &mut config::Config) {
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(timeout) = self.shutdown_timeout {
+            config.shutdown_timeout = timeout;
+        }
+        for (arg, value) in [
+            (&self.address, &mut config.address),
+            (&self.socket, &mut config.socket),
+            (&self.socket_mode, &mut config.socket_mode),
+            (&self.tls_cert, &mut config.tls.cert),
+            (&self.tls_key, &mut config.tls.key),
+            (&self.tls_redirect_http, &mut config.tls.redirect_http),
+            (&self.repo, &mut config.repo),
+            (&self.base_path, &mut config.base_path),
+            (&self.commit_url_prefix, &mut config.commit_url_prefix),
+            (&self.journal_dir, &mut config.journal_dir),
+        ] {
+            if let Some(arg) = arg {
+                value.clone_from(arg);
+            }
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Report internal links that don't lead to a page, section or file
+    Check,
+    /// Write the wiki as a static HTML site
+    Export {
+        /// Directory to write the site to
+        #[arg(short, long)]
+        out: PathBuf,
+        /// Also export private pages
+        #[arg(long)]
+        private: bool,
+    },
+    /// Import pages from a directory of markdown files or another wiki
+    Import {
+        /// Markdown directory, MediaWiki XML dump or DokuWiki data directory
+        source: PathBuf,
+        /// Format of the source. Wikis are imported with their history
+        #[arg(long, value_enum, default_value_t = Format::Markdown)]
+        from: Format,
+        /// Wiki directory to import the pages into
+        #[arg(long, default_value = "")]
+        into: String,
+        /// Author of the commit, and of wiki changes without one
+        #[arg(long, env = "WIKIMARK_USER", default_value = "import")]
+        author: String,
+        /// Replace the pages that already exist in the wiki
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Format {
+    /// A directory of markdown files, imported in a single commit
+    Markdown,
+    /// A MediaWiki XML dump with all revisions
+    Mediawiki,
+    /// A DokuWiki data directory
+    Dokuwiki,
 }
 
 pub struct WikiState {
     pub repo: git::ThreadSafeRepo,
-    pub commit_url_prefix: String,
+    pub config: config::Config,
     pub env: Environment<'static>,
+    /// Site stylesheet and the commit it was built from.
+    pub css: Mutex<Option<(gix::ObjectId, String)>>,
+    /// Page and commit counts for `/metrics` and the commit they are from.
+    pub stats: Mutex<Option<(gix::ObjectId, metrics::RepoStats)>>,
+}
+
+/// Templates from `templates/` in the wiki repo, or the built-in ones.
+fn environment(repo: git::ThreadSafeRepo, config: &config::Config) -> Environment<'static> {
+    let mut env = Environment::new();
+    env.add_global("wiki_name", config.name.clone());
+    env.add_global("base_url", config.base_url.clone());
+    env.add_global("base_path", config.base_path.clone());
+    env.add_global("show_toc", config.render.toc);
+    env.add_global("features", minijinja::Value::from_serialize(&config.features));
+    env.set_loader(move |name| {
+        if let Ok(c) = repo.local().get_file(&format!("templates/{name}")) {
+            Ok(Some(c))
+        } else {
+            Ok(TEMPLATES.get_file(name).map(|f| f.contents_utf8().unwrap().to_owned()))
+        }
+    });
+    env
 }
 
 #[tokio::main]
@@ -53,33 +188,104 @@ async fn main() -> anyhow::Result<()> {
         .with_env_filter(tracing_subscriber::EnvFilter::from_env("WIKIMARK_LOG"))
         .init();
 
-    let repo = git::ThreadSafeRepo::open(&args.repo)?;
-    let mut env = Environment::new();
-    let env_repo = repo.clone();
-    env.set_loader(move |name| {
-        if let Ok(c) = env_repo.local().get_file(&format!("templates/{name}")) {
-            Ok(Some(c))
-        } else {
-            Ok(TEMPLATES.get_file(name).map(|f| f.contents_utf8().unwrap().to_owned()))
+    // The configuration file can set where the repo is, and the repo can
+    // have its own configuration
+    let mut config = config::load(args.config.as_deref(), None)?;
+    args.apply(&mut config);
+    let repo = git::ThreadSafeRepo::open(&config.repo)?;
+    let mut config = config::load(args.config.as_deref(), Some(&repo.local()))?;
+    args.apply(&mut config);
+    config.base_path = config::normalize_base_path(&config.base_path);
+    md2html::set_theme(&config.render.theme)?;
+    routes::set_user_header(&config.auth.header)?;
+    let mut env = environment(repo.clone(), &config);
+    match args.command {
+        Some(Command::Export { out, private }) => {
+            env.add_global("export", true);
+            env.add_global("base_path", "");
+            return export::export(&repo.local(), &env, &out, private);
         }
-    });
-    let state = WikiState {
-        repo,
-        commit_url_prefix: args.commit_url_prefix,
-        env,
-    };
+        Some(Command::Check) => {
+            let broken = check::check(&repo.local(), &env, &config.base_url)?;
+            for l in &broken {
+                println!("/page/{}: {}: {}", l.page, l.href, l.reason);
+            }
+            if !broken.is_empty() {
+                anyhow::bail!("{} broken links", broken.len());
+            }
+            return Ok(());
+        }
+        Some(Command::Import { source, from, into, author, force }) => {
+            let repo = repo.local();
+            let base = import::base_path(&into);
+            return match from {
+                Format::Markdown => import::import(&repo, &source, &into, &author, force),
+                Format::Mediawiki => {
+                    let history = mediawiki::read_dump(&source, &base)?;
+                    import::replay(&repo, history, &author, force)
+                }
+                Format::Dokuwiki => {
+                    let history = dokuwiki::read_data_dir(&source, &base, &author)?;
+                    import::replay(&repo, history, &author, force)
+                }
+            };
+        }
+        None => {}
+    }
+    let listener = listen::bind(&config).await?;
+    let base_path = config.base_path.clone();
+    let shutdown_timeout = std::time::Duration::from_secs(config.shutdown_timeout);
+    let features = &config.features;
     use routes::*;
-    let app = Router::new()
+    let mut app = Router::new()
         .route("/", get(index))
         .route("/static/wiki.css", get(css))
         .route("/static/{*path}", get(assets))
         .route("/page/", get(page))
         .route("/page/{*page}", get(page))
         .route("/all", get(pages))
+        .route("/tags", get(tags))
+        .route("/tags/{tag}", get(tag))
         .route("/edit", get(edit))
         .route("/commit", post(commit))
-        .route("/changelog", get(changelog))
-        .layer(
+        .route("/healthz", get(healthz));
+    if features.journal {
+        app = app
+            .route("/journal", get(journal))
+            .route("/journal/today", get(journal_today))
+            .route("/journal/{year}/{month}", get(journal_month));
+    }
+    if features.changelog {
+        app = app.route("/changelog", get(changelog));
+    }
+    if features.check {
+        app = app.route("/check", get(broken_links));
+    }
+    if features.metrics {
+        app = app.route("/metrics", get(routes::metrics));
+    }
+    let state = Arc::new(WikiState {
+        repo,
+        config,
+        env,
+        css: Mutex::new(None),
+        stats: Mutex::new(None),
+    });
+    let app = app
+        .fallback(not_found)
+        .layer(axum::middleware::from_fn_with_state(state.clone(), render_error))
+        .layer(axum::middleware::from_fn(metrics::track))
+        .with_state(state);
+    // Behind a reverse proxy the wiki can be served under a sub-path
+    let app = if base_path.is_empty() {
+        app
+    } else {
+        let home = format!("{base_path}/page/");
+        Router::new()
+            .nest(&base_path, app)
+            .route(&format!("{base_path}/"), get(|| async move { Redirect::permanent(&home) }))
+    };
+    let app = app.layer(
             TraceLayer::new_for_http()
                 .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
                 .on_response(
@@ -87,15 +293,12 @@ async fn main() -> anyhow::Result<()> {
                         .level(Level::INFO)
                         .latency_unit(tower_http::LatencyUnit::Micros),
                 ),
-        )
-        .with_state(Arc::new(state));
+        );
 
     #[cfg(debug_assertions)]
     let app = app.layer(tower_livereload::LiveReloadLayer::new().request_predicate(
         |r: &axum::http::Request<axum::body::Body>| r.headers().get("HX-Request").is_none(),
     ));
 
-    let listener = tokio::net::TcpListener::bind(format!("{}:{}", args.address, args.port)).await?;
-    axum::serve(listener, app).await?;
-    Ok(())
+    listen::serve(listener, app, shutdown_timeout).await
 }
@@ -1,5 +1,10 @@
-use pulldown_cmark::{html, CodeBlockKind, CowStr, Event, Parser, Tag, TagEnd};
+use minijinja::{context, Environment, Value};
+use pulldown_cmark::{
+    html, BlockQuoteKind, CodeBlockKind, CowStr, Event, HeadingLevel, Options, Parser, Tag, TagEnd,
+    TextMergeStream,
+};
 use slug::slugify;
+use std::collections::{BTreeMap, HashSet};
 use std::sync::OnceLock;
 use syntect::easy::HighlightLines;
 use syntect::highlighting::ThemeSet;
@@ -7,6 +12,7 @@ use syntect::html::{
     start_highlighted_html_snippet, styled_line_to_highlighted_html, IncludeBackground,
 };
 use syntect::parsing::{SyntaxReference, SyntaxSet};
+use syntect::util::LinesWithEndings;
 
 fn get_syntax_for_block<'a>(set: &'a SyntaxSet, hint: &str) -> &'a SyntaxReference {
     set.find_syntax_by_name(hint).unwrap_or_else(|| {
@@ -15,13 +21,178 @@ fn get_syntax_for_block<'a>(set: &'a SyntaxSet, hint: &str) -> &'a SyntaxReferen
     })
 }
 
-use super::page::{Metadata, Page, Section, Toc};
+/// CSS class suffix and title of a GitHub-style `> [!KIND]` callout.
+fn callout_kind(kind: BlockQuoteKind) -> (&'static str, &'static str) {
+    match kind {
+        BlockQuoteKind::Note => ("note", "Note"),
+        BlockQuoteKind::Tip => ("tip", "Tip"),
+        BlockQuoteKind::Important => ("important", "Important"),
+        BlockQuoteKind::Warning => ("warning", "Warning"),
+        BlockQuoteKind::Caution => ("caution", "Caution"),
+    }
+}
+
+use super::git::Repo;
+use super::metrics;
+use super::page::{get_page, Metadata, Page, Section, Toc};
+use super::query::Query;
 use slab_tree::Tree;
 
 enum ParsingPhase<'a> {
     Normal,
     Code(Box<HighlightLines<'a>>),
-    Header(String),
+    Header(Heading),
+    Query(String),
+}
+
+/// Heading being collected: plain text for the TOC and slug, HTML for the page.
+#[derive(Default)]
+struct Heading {
+    title: String,
+    html: String,
+}
+
+impl Heading {
+    fn push(&mut self, event: Event) {
+        match &event {
+            Event::Text(t) | Event::Code(t) => self.title.push_str(t),
+            Event::SoftBreak | Event::HardBreak => self.title.push(' '),
+            _ => {}
+        }
+        html::push_html(&mut self.html, std::iter::once(event));
+    }
+}
+
+/// `text` escaped for HTML text and quoted attribute values.
+pub fn escape_html(text: &str) -> String {
+    let mut out = String::new();
+    let _ = pulldown_cmark_escape::escape_html(&mut out, text);
+    out
+}
+
+/// `url` escaped for a quoted `href` or `src` attribute.
+pub fn escape_href(url: &str) -> String {
+    let mut out = String::new();
+    let _ = pulldown_cmark_escape::escape_href(&mut out, url);
+    out
+}
+
+fn error_callout(msg: &str) -> String {
+    format!(
+        "<aside class=\"callout callout-caution\">\
+         <p class=\"callout-title\"><i class=\"icon-caution\"></i> Caution</p>\
+         <p>{}</p></aside>",
+        escape_html(msg)
+    )
+}
+
+/// Arguments of a `{{% name args %}}` shortcode.
+#[derive(Debug, Default, PartialEq)]
+struct Shortcode {
+    name: String,
+    args: Vec<String>,
+    kwargs: BTreeMap<String, String>,
+}
+
+/// Split the inside of a shortcode into its name, positional arguments and
+/// `key=value` arguments. Arguments containing spaces must be double-quoted.
+fn parse_shortcode(src: &str) -> Option<Shortcode> {
+    // Each token remembers where its first unquoted `=` is, if any
+    let mut tokens: Vec<(String, Option<usize>)> = vec![];
+    let mut cur: Option<(String, Option<usize>)> = None;
+    let mut quoted = false;
+    for c in src.chars() {
+        if c.is_whitespace() && !quoted {
+            tokens.extend(cur.take());
+            continue;
+        }
+        let (token, eq) = cur.get_or_insert_with(Default::default);
+        match c {
+            '"' => quoted = !quoted,
+            '=' if !quoted && eq.is_none() => {
+                *eq = Some(token.len());
+                token.push(c);
+            }
+            c => token.push(c),
+        }
+    }
+    if quoted {
+        return None;
+    }
+    tokens.extend(cur);
+
+    let mut tokens = tokens.into_iter();
+    let (name, _) = tokens.next()?;
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return None;
+    }
+    let mut shortcode = Shortcode {
+        name,
+        ..Default::default()
+    };
+    for (token, eq) in tokens {
+        match eq {
+            Some(i) if i > 0 => {
+                shortcode
+                    .kwargs
+                    .insert(token[..i].to_owned(), token[i + 1..].to_owned());
+            }
+            _ => shortcode.args.push(token),
+        }
+    }
+    Some(shortcode)
+}
+
+fn render_shortcode(env: &Environment, meta: &Metadata, src: &str) -> anyhow::Result<String> {
+    let shortcode = parse_shortcode(src).ok_or_else(|| anyhow::anyhow!("malformed shortcode"))?;
+    let templ = env.get_template(&format!("shortcodes/{}.html", shortcode.name))?;
+    Ok(templ.render(context!(
+        args => shortcode.args,
+        page => meta,
+        ..Value::from_serialize(&shortcode.kwargs)
+    ))?)
+}
+
+/// Render `text` as HTML, replacing every `{{% name args %}}` with the output
+/// of the `shortcodes/<name>.html` template.
+fn expand_shortcodes(env: &Environment, meta: &Metadata, text: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("{{%") {
+        let Some(len) = rest[start..].find("%}}") else {
+            break;
+        };
+        out.push_str(&escape_html(&rest[..start]));
+        let src = rest[start + 3..start + len].trim();
+        match render_shortcode(env, meta, src) {
+            Ok(html) => out.push_str(&html),
+            Err(e) => out.push_str(&format!(
+                "<span class=\"shortcode-error\">{}</span>",
+                escape_html(&format!("{{{{% {src} %}}}}: {e}"))
+            )),
+        }
+        rest = &rest[start + len + 3..];
+    }
+    out.push_str(&escape_html(rest));
+    out
+}
+
+/// Slugify `title`, appending `-1`, `-2`, ... if the slug was already used.
+fn unique_slug(used: &mut HashSet<String>, title: &str) -> String {
+    let mut base = slugify(title);
+    if base.is_empty() {
+        base = "section".to_owned();
+    }
+    let mut slug = base.clone();
+    let mut n = 0;
+    while !used.insert(slug.clone()) {
+        n += 1;
+        slug = format!("{base}-{n}");
+    }
+    slug
 }
 
 pub struct ParseContext {
@@ -38,11 +209,165 @@ impl ParseContext {
 }
 
 static PARSE_CONTEXT: OnceLock<ParseContext> = OnceLock::new();
+static THEME: OnceLock<String> = OnceLock::new();
+
+/// Highlight code blocks with the syntect theme `name`.
+pub fn set_theme(name: &str) -> anyhow::Result<()> {
+    let themes = &PARSE_CONTEXT.get_or_init(ParseContext::new).theme_set.themes;
+    if !themes.contains_key(name) {
+        let known: Vec<_> = themes.keys().map(String::as_str).collect();
+        anyhow::bail!("unknown theme `{name}`, use one of {}", known.join(", "));
+    }
+    THEME
+        .set(name.to_owned())
+        .map_err(|_| anyhow::anyhow!("theme already set"))
+}
+
+/// Maximum nesting depth of `{{< include >}}` directives.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Target of an `{{< include "path/to/page#section" >}}` directive line.
+fn include_target(line: &str) -> Option<&str> {
+    let inner = line.trim().strip_prefix("{{<")?.strip_suffix(">}}")?.trim();
+    let arg = inner.strip_prefix("include")?.trim();
+    arg.strip_prefix('"')?.strip_suffix('"')
+}
+
+/// Markdown of the section of `md` whose heading id is `section`, up to the
+/// next heading of the same or a higher level.
+fn extract_section<'a>(md: &'a str, section: &str) -> Option<&'a str> {
+    let mut slugs = HashSet::new();
+    let mut found: Option<(usize, HeadingLevel)> = None;
+    let mut heading: Option<(usize, HeadingLevel, Heading)> = None;
+    for (event, range) in Parser::new_ext(md, Options::ENABLE_GFM).into_offset_iter() {
+        match event {
+            Event::Start(Tag::Heading { level, .. }) => {
+                if let Some((start, found_level)) = found
+                    && level <= found_level
+                {
+                    return Some(&md[start..range.start]);
+                }
+                heading = Some((range.start, level, Heading::default()));
+            }
+            Event::End(TagEnd::Heading(_)) => {
+                let (start, level, h) = heading.take().unwrap();
+                if found.is_none() && unique_slug(&mut slugs, &h.title) == section {
+                    found = Some((start, level));
+                }
+            }
+            event => {
+                if let Some((_, _, h)) = &mut heading {
+                    h.push(event);
+                }
+            }
+        }
+    }
+    found.map(|(start, _)| &md[start..])
+}
 
-pub fn parse(md: &str, meta: &Metadata) -> Page {
+fn include(
+    repo: &Repo,
+    target: &str,
+    meta: &Metadata,
+    stack: &mut Vec<String>,
+) -> anyhow::Result<String> {
+    let (path, section) = match target.split_once('#') {
+        Some((path, section)) => (path, Some(section)),
+        None => (target, None),
+    };
+    let path = path.trim_start_matches('/');
+    if stack.iter().any(|p| p == path) {
+        anyhow::bail!("include cycle through `{path}`");
+    }
+    if stack.len() >= MAX_INCLUDE_DEPTH {
+        anyhow::bail!("includes nested more than {MAX_INCLUDE_DEPTH} levels deep");
+    }
+    let (page, _) = get_page(repo, path)?;
+    if page.meta.private && !meta.private {
+        anyhow::bail!("a private page can only be included in private pages");
+    }
+    let md = match section {
+        Some(s) => {
+            extract_section(&page.content, s).ok_or_else(|| anyhow::anyhow!("no section `{s}`"))?
+        }
+        None => &page.content,
+    };
+    stack.push(path.to_owned());
+    let md = expand_includes(repo, md, meta, stack);
+    stack.pop();
+    Ok(md)
+}
+
+/// Replace every include directive outside of fenced code blocks with the
+/// markdown it refers to. Failed includes become a caution callout.
+fn expand_includes(repo: &Repo, md: &str, meta: &Metadata, stack: &mut Vec<String>) -> String {
+    // Directives in fenced or indented code are shown as they are
+    let code: Vec<_> = Parser::new_ext(md, Options::ENABLE_GFM)
+        .into_offset_iter()
+        .filter_map(|(event, range)| match event {
+            Event::Start(Tag::CodeBlock(_)) => Some(range),
+            _ => None,
+        })
+        .collect();
+    let mut out = String::with_capacity(md.len());
+    let mut start = 0;
+    for line in md.split_inclusive('\n') {
+        let end = start + line.len();
+        // The range of indented code starts after the indentation
+        let in_code = code.iter().any(|r| r.start < end && start < r.end);
+        start = end;
+        if !in_code && let Some(target) = include_target(line) {
+            out.push('\n');
+            match include(repo, target, meta, stack) {
+                Ok(md) => out.push_str(&md),
+                Err(e) => {
+                    out.push_str(&format!("> [!CAUTION]\n> Cannot include `{target}`: {e}\n"))
+                }
+            }
+            out.push_str("\n\n");
+            continue;
+        }
+        out.push_str(line);
+    }
+    out
+}
+
+/// Render the page at `link`, whose content is `md`.
+pub fn parse(repo: &Repo, env: &Environment, link: &str, md: &str, meta: &Metadata) -> Page {
+    let _timer = metrics::time_render();
+    // A page including itself is a cycle from the start
+    let mut stack = vec![link.to_owned()];
+    render(
+        repo,
+        env,
+        &expand_includes(repo, md, meta, &mut stack),
+        meta,
+    )
+}
+
+/// URL path the wiki is served under, from the `base_path` template global.
+pub fn base_path(env: &Environment) -> String {
+    env.globals()
+        .find(|(name, _)| *name == "base_path")
+        .and_then(|(_, v)| v.as_str().map(str::to_owned))
+        .unwrap_or_default()
+}
+
+/// Absolute form of the link `url` found in page content.
+fn with_base_path<'a>(base_path: &str, url: CowStr<'a>) -> CowStr<'a> {
+    if url.starts_with('/') && !url.starts_with("//") && !base_path.is_empty() {
+        CowStr::from(format!("{base_path}{url}"))
+    } else {
+        url
+    }
+}
+
+fn render(repo: &Repo, env: &Environment, md: &str, meta: &Metadata) -> Page {
+    let base_path = base_path(env);
     let parse_context = PARSE_CONTEXT.get_or_init(ParseContext::new);
-    let theme = &parse_context.theme_set.themes["base16-ocean.dark"];
-    let parser = Parser::new(md);
+    let theme = THEME.get().map_or("base16-ocean.dark", String::as_str);
+    let theme = &parse_context.theme_set.themes[theme];
+    let parser = TextMergeStream::new(Parser::new_ext(md, Options::ENABLE_GFM));
     let mut out = String::new();
     let mut phase = ParsingPhase::Normal;
     let mut toc_tree = Tree::new();
@@ -52,81 +377,147 @@ pub fn parse(md: &str, meta: &Metadata) -> Page {
         level: 0,
     });
     let mut cur_section = toc_tree.root_mut().unwrap().node_id();
+    let mut slugs = HashSet::new();
 
     {
         let toc = &mut toc_tree;
-        let parser = parser.filter_map(move |event| match event {
-            Event::Start(Tag::CodeBlock(ref info)) => {
-                let info = match info {
-                    CodeBlockKind::Indented => "",
-                    CodeBlockKind::Fenced(i) => i,
-                };
-                let syntax = get_syntax_for_block(&parse_context.syntax_set, info);
-                let highlighter = Box::new(HighlightLines::new(syntax, theme));
-                phase = ParsingPhase::Code(highlighter);
-                let snippet = start_highlighted_html_snippet(theme);
-                Some(Event::Html(CowStr::Boxed(snippet.0.into_boxed_str())))
-            }
-            Event::End(TagEnd::CodeBlock) => {
-                phase = ParsingPhase::Normal;
-                Some(Event::Html(CowStr::Borrowed("</pre>")))
+        let parser = parser.filter_map(move |event| {
+            if let ParsingPhase::Header(ref mut h) = phase
+                && !matches!(event, Event::End(TagEnd::Heading(_)))
+            {
+                h.push(event);
+                return None;
             }
-            Event::Text(text) => match phase {
-                ParsingPhase::Code(ref mut highlighter) => {
-                    let ranges = highlighter
-                        .highlight_line(&text, &parse_context.syntax_set)
-                        .unwrap();
-                    let h = styled_line_to_highlighted_html(&ranges[..], IncludeBackground::Yes)
-                        .unwrap();
-                    Some(Event::Html(CowStr::Boxed(h.into_boxed_str())))
-                }
-                ParsingPhase::Header(ref mut h) => {
-                    h.push_str(&text);
+            match event {
+                Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(ref info)))
+                    if info.as_ref() == "wikiquery" =>
+                {
+                    phase = ParsingPhase::Query(String::new());
                     None
                 }
-                ParsingPhase::Normal => Some(Event::Text(text)),
-            },
-            Event::Start(Tag::Heading { level, .. }) => {
-                let level = level as i32;
-                if level <= toc.get_mut(cur_section).unwrap().data().level {
+                Event::End(TagEnd::CodeBlock) if matches!(phase, ParsingPhase::Query(_)) => {
+                    let src = match std::mem::replace(&mut phase, ParsingPhase::Normal) {
+                        ParsingPhase::Query(src) => src,
+                        _ => unreachable!(),
+                    };
+                    let html = Query::parse(&src)
+                        .and_then(|q| q.render(repo, &base_path, meta.private))
+                        .unwrap_or_else(|e| error_callout(&format!("Invalid wikiquery: {e}")));
+                    Some(Event::Html(CowStr::from(html)))
+                }
+                Event::Start(Tag::CodeBlock(ref info)) => {
+                    let info = match info {
+                        CodeBlockKind::Indented => "",
+                        CodeBlockKind::Fenced(i) => i,
+                    };
+                    let syntax = get_syntax_for_block(&parse_context.syntax_set, info);
+                    let highlighter = Box::new(HighlightLines::new(syntax, theme));
+                    phase = ParsingPhase::Code(highlighter);
+                    let snippet = start_highlighted_html_snippet(theme);
+                    Some(Event::Html(CowStr::Boxed(snippet.0.into_boxed_str())))
+                }
+                Event::End(TagEnd::CodeBlock) => {
+                    phase = ParsingPhase::Normal;
+                    Some(Event::Html(CowStr::Borrowed("</pre>")))
+                }
+                Event::Start(Tag::Link {
+                    link_type,
+                    dest_url,
+                    title,
+                    id,
+                }) => Some(Event::Start(Tag::Link {
+                    link_type,
+                    dest_url: with_base_path(&base_path, dest_url),
+                    title,
+                    id,
+                })),
+                Event::Start(Tag::Image {
+                    link_type,
+                    dest_url,
+                    title,
+                    id,
+                }) => Some(Event::Start(Tag::Image {
+                    link_type,
+                    dest_url: with_base_path(&base_path, dest_url),
+                    title,
+                    id,
+                })),
+                Event::Start(Tag::BlockQuote(Some(kind))) => {
+                    let (class, title) = callout_kind(kind);
+                    Some(Event::Html(CowStr::from(format!(
+                        "<aside class=\"callout callout-{class}\">\
+                         <p class=\"callout-title\"><i class=\"icon-{class}\"></i> {title}</p>"
+                    ))))
+                }
+                Event::End(TagEnd::BlockQuote(Some(_))) => {
+                    Some(Event::Html(CowStr::Borrowed("</aside>")))
+                }
+                Event::Text(text) => match phase {
+                    ParsingPhase::Code(ref mut highlighter) => {
+                        let mut h = String::new();
+                        for line in LinesWithEndings::from(&text) {
+                            let ranges = highlighter
+                                .highlight_line(line, &parse_context.syntax_set)
+                                .unwrap();
+                            h.push_str(
+                                &styled_line_to_highlighted_html(
+                                    &ranges[..],
+                                    IncludeBackground::Yes,
+                                )
+                                .unwrap(),
+                            );
+                        }
+                        Some(Event::Html(CowStr::Boxed(h.into_boxed_str())))
+                    }
+                    ParsingPhase::Query(ref mut src) => {
+                        src.push_str(&text);
+                        None
+                    }
+                    _ if text.contains("{{%") => Some(Event::InlineHtml(CowStr::from(
+                        expand_shortcodes(env, meta, &text),
+                    ))),
+                    _ => Some(Event::Text(text)),
+                },
+                Event::Start(Tag::Heading { level, .. }) => {
+                    let level = level as i32;
+                    // The root has level 0, so this always stops before running out of parents.
+                    while level <= toc.get(cur_section).unwrap().data().level {
+                        match toc.get(cur_section).unwrap().parent() {
+                            Some(p) => cur_section = p.node_id(),
+                            None => break,
+                        }
+                    }
                     cur_section = toc
                         .get_mut(cur_section)
                         .unwrap()
-                        .parent()
-                        .expect("no parent")
+                        .append(Section {
+                            link: String::new(),
+                            title: String::new(),
+                            level,
+                        })
                         .node_id();
+                    phase = ParsingPhase::Header(Heading::default());
+                    None
                 }
-                cur_section = toc
-                    .get_mut(cur_section)
-                    .unwrap()
-                    .append(Section {
-                        link: String::new(),
-                        title: String::new(),
-                        level,
-                    })
-                    .node_id();
-                phase = ParsingPhase::Header(String::new());
-                None
-            }
-            Event::End(TagEnd::Heading(_)) => {
-                let mut cur_phase = ParsingPhase::Normal;
-                std::mem::swap(&mut cur_phase, &mut phase);
-                let h = match cur_phase {
-                    ParsingPhase::Header(h) => h,
-                    _ => panic!("impossible phase"),
-                };
-                let mut sec = toc.get_mut(cur_section).unwrap();
-                let data = sec.data();
-                data.link = slugify(&h);
-                data.title = h;
-                Some(Event::Html(CowStr::from(format!(
-                    "<h{n} id=\"{id}\">{t} <a class=\"zola-anchor\" href=\"#{id}\">🔗</a></h{n}>",
-                    n = data.level,
-                    id = data.link,
-                    t = data.title
-                ))))
+                Event::End(TagEnd::Heading(_)) => {
+                    let h = match std::mem::replace(&mut phase, ParsingPhase::Normal) {
+                        ParsingPhase::Header(h) => h,
+                        _ => panic!("impossible phase"),
+                    };
+                    let mut sec = toc.get_mut(cur_section).unwrap();
+                    let data = sec.data();
+                    data.link = unique_slug(&mut slugs, &h.title);
+                    data.title = h.title;
+                    Some(Event::Html(CowStr::from(format!(
+                        "<h{n} id=\"{id}\">{t} \
+                         <a class=\"zola-anchor\" href=\"#{id}\">🔗</a></h{n}>",
+                        n = data.level,
+                        id = data.link,
+                        t = h.html
+                    ))))
+                }
+                _ => Some(event),
             }
-            _ => Some(event),
         });
         html::push_html(&mut out, parser);
     }
@@ -135,3 +526,128 @@ pub fn parse(md: &str, meta: &Metadata) -> Page {
         content: out,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::ThreadSafeRepo;
+
+    fn test_repo() -> (tempfile::TempDir, Repo) {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let repo = ThreadSafeRepo::open(tmp.path().to_str().unwrap())
+            .unwrap()
+            .local();
+        (tmp, repo)
+    }
+
+    fn toc_of(md: &str) -> Toc {
+        let meta = Metadata {
+            title: "Test".to_owned(),
+            private: false,
+            tags: vec![],
+            other: Default::default(),
+        };
+        let (_tmp, repo) = test_repo();
+        render(&repo, &Environment::new(), md, &meta).toc
+    }
+
+    #[test]
+    fn toc_handles_skipped_levels() {
+        let toc = toc_of("## Intro\n### Details\n# Top\n### Deep\n## Mid\n");
+        let top: Vec<_> = toc.0.children.iter().map(|c| &c.section.title).collect();
+        assert_eq!(top, ["Intro", "Top"]);
+        assert_eq!(toc.0.children[0].children[0].section.title, "Details");
+        let under_top: Vec<_> = toc.0.children[1]
+            .children
+            .iter()
+            .map(|c| &c.section.title)
+            .collect();
+        assert_eq!(under_top, ["Deep", "Mid"]);
+    }
+
+    #[test]
+    fn toc_deduplicates_slugs() {
+        let toc = toc_of("# Intro\n# Intro\n# Intro-1\n");
+        let links: Vec<_> = toc.0.children.iter().map(|c| &c.section.link).collect();
+        assert_eq!(links, ["intro", "intro-1", "intro-1-1"]);
+    }
+
+    #[test]
+    fn heading_keeps_inline_formatting() {
+        let meta = Metadata {
+            title: "Test".to_owned(),
+            private: false,
+            tags: vec![],
+            other: Default::default(),
+        };
+        let (_tmp, repo) = test_repo();
+        let page = render(
+            &repo,
+            &Environment::new(),
+            "# The `run` *command*\n",
+            &meta,
+        );
+        assert_eq!(page.toc.0.children[0].section.title, "The run command");
+        assert!(page
+            .content
+            .contains("The <code>run</code> <em>command</em>"));
+    }
+
+    #[test]
+    fn section_extraction_stops_at_same_level() {
+        let md = "# A\na\n## B\nb\n### C\nc\n## D\nd\n";
+        assert_eq!(extract_section(md, "b"), Some("## B\nb\n### C\nc\n"));
+        assert_eq!(extract_section(md, "d"), Some("## D\nd\n"));
+        assert_eq!(extract_section(md, "e"), None);
+    }
+
+    #[test]
+    fn self_include_is_a_cycle() {
+        let (_tmp, repo) = test_repo();
+        let md = "once\n{{< include \"self-include\" >}}\n";
+        repo.commit(&crate::git::CommitData {
+            msg: "Self include".to_owned(),
+            author: "test".to_owned(),
+            added: vec![("self-include.md".to_owned(), md.to_owned())],
+            removed: vec![],
+            timestamp: None,
+        })
+        .unwrap();
+        let page = parse(&repo, &Environment::new(), "self-include", md, &Metadata::default());
+        assert_eq!(page.content.matches("once").count(), 1);
+        assert!(page.content.contains("include cycle through"));
+    }
+
+    #[test]
+    fn no_includes_in_code() {
+        let (_tmp, repo) = test_repo();
+        let inc = "{{< include \"missing\" >}}\n";
+        for md in [
+            format!("````\n```\n{inc}````\n"),
+            format!("~~~\n{inc}~~~\n"),
+            format!("text\n\n    code\n    {inc}"),
+            format!("\t{inc}"),
+        ] {
+            assert_eq!(expand_includes(&repo, &md, &Metadata::default(), &mut vec![]), md);
+        }
+        let md = format!("- item\n\n    {inc}");
+        let expanded = expand_includes(&repo, &md, &Metadata::default(), &mut vec![]);
+        assert!(expanded.contains("Cannot include `missing`"));
+    }
+
+    #[test]
+    fn attribute_escaping() {
+        assert_eq!(escape_html("a\"b<c>"), "a&quot;b&lt;c&gt;");
+        assert_eq!(escape_href("/wiki\"x/a b"), "/wiki%22x/a%20b");
+    }
+
+    #[test]
+    fn shortcode_arguments() {
+        let sc = parse_shortcode(r#"badge "very deprecated" color=red "a=b""#).unwrap();
+        assert_eq!(sc.name, "badge");
+        assert_eq!(sc.args, ["very deprecated", "a=b"]);
+        assert_eq!(sc.kwargs["color"], "red");
+        assert_eq!(parse_shortcode("../etc x"), None);
+        assert_eq!(parse_shortcode(r#"badge "open"#), None);
+    }
+}
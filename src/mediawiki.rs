@@ -0,0 +1,468 @@
+use chrono::DateTime;
+use quick_xml::escape::resolve_predefined_entity;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use slug::slugify;
+use std::path::Path;
+
+use crate::import::{markdown_table, History, Revision};
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+/// Wiki path of the page `title`, under `base`. Namespaces and subpages
+/// become directories.
+fn page_link(base: &str, title: &str) -> String {
+    let path: Vec<_> = title
+        .split(['/', ':'])
+        .map(slugify)
+        .filter(|s| !s.is_empty())
+        .collect();
+    format!("{base}{}", path.join("/"))
+}
+
+/// Markdown for the internal link `[[target|label]]` of a page under `base`.
+fn internal_link(base: &str, target: &str, label: Option<&str>, tags: &mut Vec<String>) -> String {
+    let target = target.trim();
+    // A leading colon links to a category or file instead of using it
+    let (target, escaped) = match target.strip_prefix(':') {
+        Some(t) => (t, true),
+        None => (target, false),
+    };
+    let (ns, name) = target.split_once(':').unwrap_or(("", target));
+    match ns.trim().to_lowercase().as_str() {
+        "category" if !escaped => {
+            tags.push(name.trim().to_owned());
+            return String::new();
+        }
+        "file" | "image" | "media" if !escaped => {
+            let caption = label.and_then(|l| l.rsplit('|').next()).unwrap_or(name);
+            return format!("![{}](<{}>)", caption.trim(), name.trim());
+        }
+        _ => {}
+    }
+    let (page, section) = target.split_once('#').unwrap_or((target, ""));
+    let label = label.unwrap_or(target).trim();
+    let anchor = if section.is_empty() {
+        String::new()
+    } else {
+        format!("#{}", slugify(section))
+    };
+    if page.is_empty() {
+        format!("[{label}]({anchor})")
+    } else {
+        format!("[{label}](/page/{}{anchor})", page_link(base, page))
+    }
+}
+
+/// Convert inline MediaWiki markup: links, emphasis and a few HTML tags.
+fn inline(base: &str, line: &str, tags: &mut Vec<String>) -> String {
+    let mut out = String::new();
+    let mut rest = line;
+    while let Some(start) = rest.find('[') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(inner) = rest.strip_prefix("[[")
+            && let Some(end) = inner.find("]]")
+        {
+            let (target, label) = match inner[..end].split_once('|') {
+                Some((t, l)) => (t, Some(l)),
+                None => (&inner[..end], None),
+            };
+            out.push_str(&internal_link(base, target, label, tags));
+            rest = &inner[end + 2..];
+        } else if let Some(end) = rest.find(']')
+            && rest[1..end].contains("://")
+        {
+            match rest[1..end].split_once(' ') {
+                Some((url, label)) => out.push_str(&format!("[{}]({url})", label.trim())),
+                None => out.push_str(&format!("<{}>", &rest[1..end])),
+            }
+            rest = &rest[end + 1..];
+        } else {
+            out.push('[');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    [
+        ("'''''", "***"),
+        ("'''", "**"),
+        ("''", "*"),
+        ("<code>", "`"),
+        ("</code>", "`"),
+        ("<tt>", "`"),
+        ("</tt>", "`"),
+        ("<s>", "~~"),
+        ("</s>", "~~"),
+        ("<del>", "~~"),
+        ("</del>", "~~"),
+        ("<nowiki>", ""),
+        ("</nowiki>", ""),
+    ]
+    .iter()
+    .fold(out, |s, (from, to)| s.replace(from, to))
+}
+
+/// Rows of a `{| ... |}` table, from the lines after its start.
+fn table<'a>(
+    lines: &mut impl Iterator<Item = &'a str>,
+    base: &str,
+    tags: &mut Vec<String>,
+) -> String {
+    let mut rows: Vec<Vec<String>> = vec![];
+    let mut caption = None;
+    for line in lines.by_ref() {
+        let line = line.trim();
+        if line.starts_with("|}") {
+            break;
+        } else if let Some(c) = line.strip_prefix("|+") {
+            caption = Some(inline(base, c.trim(), tags));
+        } else if line.starts_with("|-") {
+            rows.push(vec![]);
+        } else if let Some(cells) = line.strip_prefix('!').or_else(|| line.strip_prefix('|')) {
+            if rows.is_empty() {
+                rows.push(vec![]);
+            }
+            let row = rows.last_mut().unwrap();
+            for cell in cells.split("||").flat_map(|c| c.split("!!")) {
+                // Cells can start with attributes: `style="..." | text`
+                let text = match cell.split_once('|') {
+                    Some((attrs, text)) if !attrs.contains("[[") => text,
+                    _ => cell,
+                };
+                row.push(inline(base, text, tags));
+            }
+        } else if let Some(cell) = rows.last_mut().and_then(|r| r.last_mut()) {
+            cell.push(' ');
+            cell.push_str(&inline(base, line, tags));
+        }
+    }
+    rows.retain(|r| !r.is_empty());
+    let mut out = caption.map(|c| format!("**{c}**\n\n")).unwrap_or_default();
+    out.push_str(&markdown_table(&rows));
+    out
+}
+
+/// Convert MediaWiki markup to markdown. Links point to pages under `base`,
+/// categories are returned as tags.
+pub fn convert(text: &str, base: &str) -> (String, Vec<String>) {
+    let mut out = String::new();
+    let mut tags = vec![];
+    let mut lines = text.lines();
+    // Closing tag of the preformatted block we are in
+    let mut pre: Option<&str> = None;
+    let mut indented = false;
+    while let Some(line) = lines.next() {
+        if let Some(end) = pre {
+            match line.find(end) {
+                Some(i) => {
+                    out.push_str(&line[..i]);
+                    if i > 0 {
+                        out.push('\n');
+                    }
+                    out.push_str("```\n");
+                    pre = None;
+                }
+                None => {
+                    out.push_str(line);
+                    out.push('\n');
+                }
+            }
+            continue;
+        }
+        if indented && !line.starts_with(' ') {
+            out.push_str("```\n");
+            indented = false;
+        }
+        let trimmed = line.trim();
+        if let Some((tag, end)) = [
+            ("<pre", "</pre>"),
+            ("<syntaxhighlight", "</syntaxhighlight>"),
+            ("<source", "</source>"),
+        ]
+        .into_iter()
+        .find(|(tag, _)| trimmed.starts_with(tag))
+            && let Some(open) = trimmed.find('>')
+        {
+            let lang = trimmed[tag.len()..open]
+                .split_once("lang=")
+                .map(|(_, l)| l.trim_matches(['"', '\'', ' ', '/']))
+                .and_then(|l| l.split(['"', '\'', ' ']).next())
+                .unwrap_or_default();
+            out.push_str(&format!("```{lang}\n"));
+            let rest = &trimmed[open + 1..];
+            match rest.find(end) {
+                Some(i) => out.push_str(&format!("{}\n```\n", &rest[..i])),
+                None if rest.is_empty() => pre = Some(end),
+                None => {
+                    out.push_str(rest);
+                    out.push('\n');
+                    pre = Some(end);
+                }
+            }
+        } else if trimmed.starts_with("{|") {
+            out.push_str(&table(&mut lines, base, &mut tags));
+        } else if line.starts_with(' ') && !trimmed.is_empty() {
+            if !indented {
+                out.push_str("```\n");
+                indented = true;
+            }
+            out.push_str(&line[1..]);
+            out.push('\n');
+        } else if trimmed.starts_with("----") {
+            out.push_str("---\n");
+        } else if trimmed.len() > 2 && trimmed.starts_with('=') && trimmed.ends_with('=') {
+            let level = trimmed.len() - trimmed.trim_start_matches('=').len();
+            let level = level.min(trimmed.len() - trimmed.trim_end_matches('=').len());
+            let title = trimmed.trim_matches('=').trim();
+            let title = inline(base, title, &mut tags);
+            out.push_str(&format!("{} {title}\n", "#".repeat(level.min(6))));
+        } else if line.starts_with(['*', '#', ':', ';']) {
+            let marks: String = line.chars().take_while(|c| "*#:;".contains(*c)).collect();
+            let indent = "    ".repeat(marks.len() - 1);
+            let item = inline(base, line[marks.len()..].trim(), &mut tags);
+            match marks.chars().last() {
+                Some('*') => out.push_str(&format!("{indent}- {item}\n")),
+                Some('#') => out.push_str(&format!("{indent}1. {item}\n")),
+                Some(';') => match item.split_once(" : ") {
+                    Some((term, def)) => out.push_str(&format!("{indent}**{term}**: {def}\n")),
+                    None => out.push_str(&format!("{indent}**{item}**\n")),
+                },
+                _ if marks.chars().all(|c| c == ':') => {
+                    out.push_str(&format!("{} {item}\n", ">".repeat(marks.len())));
+                }
+                _ => out.push_str(&format!("{indent}  {item}\n")),
+            }
+        } else {
+            let line = ["__TOC__", "__NOTOC__", "__FORCETOC__", "__NOEDITSECTION__"]
+                .iter()
+                .fold(line.to_owned(), |l, word| l.replace(word, ""));
+            let converted = inline(base, &line, &mut tags);
+            if converted.trim().is_empty() && !trimmed.is_empty() {
+                // Lines left empty by categories or magic words
+                continue;
+            }
+            out.push_str(&converted);
+            out.push('\n');
+        }
+    }
+    if pre.is_some() || indented {
+        out.push_str("```\n");
+    }
+    (out, tags)
+}
+
+#[derive(Default)]
+struct PageData {
+    title: String,
+    namespaced: bool,
+    redirect: Option<String>,
+    revisions: Vec<RevisionData>,
+}
+
+#[derive(Default)]
+struct RevisionData {
+    timestamp: i64,
+    author: String,
+    comment: String,
+    text: String,
+}
+
+fn add_page(history: &mut History, page: PageData, base: &str) {
+    let link = page_link(base, &page.title);
+    let last = page
+        .revisions
+        .last()
+        .map(|r| r.text.trim_start().to_lowercase());
+    let redirect = page.redirect.or_else(|| {
+        let text = last.as_deref()?.strip_prefix("#redirect")?;
+        let target = text.split_once("[[")?.1.split_once("]]")?.0;
+        Some(target.split('|').next()?.to_owned())
+    });
+    if let Some(target) = redirect {
+        let page = target.split('#').next().unwrap_or_default();
+        history.redirects.insert(link, page_link(base, page));
+        return;
+    }
+    // Namespaces and subpages are part of the path, not of the title
+    let title = match page.namespaced {
+        true => page
+            .title
+            .split_once(':')
+            .map_or(page.title.as_str(), |(_, t)| t),
+        false => &page.title,
+    };
+    let title = title.rsplit('/').next().unwrap_or(title).trim().to_owned();
+    for rev in page.revisions {
+        let (content, tags) = convert(&rev.text, base);
+        history.revisions.push(Revision {
+            link: link.clone(),
+            title: title.clone(),
+            tags,
+            content,
+            author: rev.author,
+            timestamp: rev.timestamp,
+            comment: rev.comment,
+        });
+    }
+}
+
+/// Read every page of a MediaWiki XML dump with all its revisions, for pages
+/// under `base`.
+pub fn read_dump(path: &Path, base: &str) -> Result<History> {
+    let mut reader = Reader::from_file(path)?;
+    let mut buf = vec![];
+    let mut history = History::default();
+    let mut stack: Vec<String> = vec![];
+    let mut text = String::new();
+    let mut page = PageData::default();
+    let mut rev = RevisionData::default();
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(e) => {
+                stack.push(String::from_utf8_lossy(e.name().as_ref()).into_owned());
+                text.clear();
+            }
+            Event::Empty(e) if e.name().as_ref() == b"redirect" => {
+                if let Some(title) = e.try_get_attribute("title")? {
+                    page.redirect = Some(title.unescape_value()?.into_owned());
+                }
+            }
+            Event::Text(t) => text.push_str(&t.xml_content()?),
+            Event::CData(t) => text.push_str(&t.decode()?),
+            Event::GeneralRef(r) => match r.resolve_char_ref()? {
+                Some(c) => text.push(c),
+                None => text.push_str(resolve_predefined_entity(&r.decode()?).unwrap_or_default()),
+            },
+            Event::End(_) => {
+                let name = stack.pop().unwrap_or_default();
+                let value = std::mem::take(&mut text);
+                match (stack.last().map(String::as_str), name.as_str()) {
+                    (Some("page"), "title") => page.title = value,
+                    (Some("page"), "ns") => page.namespaced = value.trim() != "0",
+                    (Some("revision"), "timestamp") => {
+                        rev.timestamp = DateTime::parse_from_rfc3339(value.trim())?.timestamp();
+                    }
+                    (Some("contributor"), "username" | "ip") => rev.author = value,
+                    (Some("revision"), "comment") => rev.comment = value,
+                    (Some("revision"), "text") => rev.text = value,
+                    (_, "revision") => {
+                        if rev.author.is_empty() {
+                            rev.author = "unknown".to_owned();
+                        }
+                        page.revisions.push(std::mem::take(&mut rev));
+                    }
+                    (_, "page") => add_page(&mut history, std::mem::take(&mut page), base),
+                    _ => {}
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(history)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markup() {
+        let text = concat!(
+            "== Setup ==\n",
+            "Run '''this''' on [[Help:Build tools|the tools]] page, see [https://example.com docs].\n",
+            "* one\n",
+            "** nested ''item''\n",
+            "# first\n",
+            "<syntaxhighlight lang=\"rust\">\n",
+            "fn main() {}\n",
+            "</syntaxhighlight>\n",
+            "{|\n",
+            "! A !! B\n",
+            "|-\n",
+            "| 1 || [[Other#Part]]\n",
+            "|}\n",
+            "[[Category:Ops]]\n",
+        );
+        let (md, tags) = convert(text, "old/");
+        assert_eq!(
+            md,
+            concat!(
+                "## Setup\n",
+                "Run **this** on [the tools](/page/old/help/build-tools) page, ",
+                "see [docs](https://example.com).\n",
+                "- one\n",
+                "    - nested *item*\n",
+                "1. first\n",
+                "```rust\n",
+                "fn main() {}\n",
+                "```\n",
+                "| A | B |\n",
+                "| --- | --- |\n",
+                "| 1 | [Other#Part](/page/old/other#part) |\n",
+            )
+        );
+        assert_eq!(tags, ["Ops"]);
+    }
+
+    #[test]
+    fn dump() {
+        let path = std::env::temp_dir().join(format!("wikimark-dump-{}.xml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"<mediawiki>
+  <page>
+    <title>Help:Build &amp; test</title>
+    <ns>12</ns>
+    <revision>
+      <timestamp>2010-01-02T03:04:05Z</timestamp>
+      <contributor><username>Alice</username></contributor>
+      <comment>First</comment>
+      <text xml:space="preserve">Hello &lt;b&gt;</text>
+    </revision>
+    <revision>
+      <timestamp>2011-01-02T03:04:05Z</timestamp>
+      <contributor><ip>10.0.0.1</ip></contributor>
+      <text xml:space="preserve">Bye</text>
+    </revision>
+  </page>
+  <page>
+    <title>Old name</title>
+    <ns>0</ns>
+    <redirect title="Help:Build &amp; test" />
+    <revision>
+      <timestamp>2012-01-02T03:04:05Z</timestamp>
+      <contributor><username>Bob</username></contributor>
+      <text xml:space="preserve">#REDIRECT [[Help:Build &amp; test]]</text>
+    </revision>
+  </page>
+</mediawiki>"#,
+        )
+        .unwrap();
+        let history = read_dump(&path, "").unwrap();
+        std::fs::remove_file(path).unwrap();
+        let revs: Vec<_> = history
+            .revisions
+            .iter()
+            .map(|r| {
+                (
+                    r.link.as_str(),
+                    r.title.as_str(),
+                    r.author.as_str(),
+                    r.timestamp,
+                )
+            })
+            .collect();
+        assert_eq!(
+            revs,
+            [
+                ("help/build-test", "Build & test", "Alice", 1262401445),
+                ("help/build-test", "Build & test", "10.0.0.1", 1293937445),
+            ]
+        );
+        assert_eq!(history.revisions[0].content, "Hello <b>\n");
+        assert_eq!(history.redirects["old-name"], "help/build-test");
+    }
+}
@@ -0,0 +1,252 @@
+use axum::extract::{MatchedPath, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, PoisonError};
+use std::time::Instant;
+
+use crate::git::Repo;
+use crate::page::list_files;
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: [f64; 10] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0, 5.0];
+
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Histogram {
+            buckets: [0; BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, seconds: f64) {
+        for (bucket, le) in self.buckets.iter_mut().zip(BUCKETS) {
+            if seconds <= le {
+                *bucket += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    /// Lines of the histogram `name`, with `labels` before the bucket one.
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        for (count, le) in self.buckets.iter().zip(BUCKETS) {
+            let _ = writeln!(out, "{name}_bucket{{{labels}{sep}le=\"{le}\"}} {count}");
+        }
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{{labels}{sep}le=\"+Inf\"}} {}",
+            self.count
+        );
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{labels}}}")
+        };
+        let _ = writeln!(out, "{name}_sum{labels} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{labels} {}", self.count);
+    }
+}
+
+struct Metrics {
+    /// Requests by route, method and status.
+    requests: BTreeMap<(String, String, u16), u64>,
+    request_duration: BTreeMap<String, Histogram>,
+    render_duration: Histogram,
+    git_duration: BTreeMap<&'static str, Histogram>,
+}
+
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics {
+    requests: BTreeMap::new(),
+    request_duration: BTreeMap::new(),
+    render_duration: Histogram::new(),
+    git_duration: BTreeMap::new(),
+});
+
+fn metrics() -> std::sync::MutexGuard<'static, Metrics> {
+    METRICS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+enum Timed {
+    Render,
+    Git(&'static str),
+}
+
+/// Records the time since it was created when dropped.
+pub struct Timer {
+    timed: Timed,
+    start: Instant,
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let seconds = self.start.elapsed().as_secs_f64();
+        let mut metrics = metrics();
+        match self.timed {
+            Timed::Render => metrics.render_duration.observe(seconds),
+            Timed::Git(op) => metrics
+                .git_duration
+                .entry(op)
+                .or_insert_with(Histogram::new)
+                .observe(seconds),
+        }
+    }
+}
+
+/// Time the rendering of a page to HTML.
+pub fn time_render() -> Timer {
+    Timer {
+        timed: Timed::Render,
+        start: Instant::now(),
+    }
+}
+
+/// Time the git operation `op`.
+pub fn time_git(op: &'static str) -> Timer {
+    Timer {
+        timed: Timed::Git(op),
+        start: Instant::now(),
+    }
+}
+
+/// Middleware counting requests and their latency by route.
+pub async fn track(req: Request, next: Next) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", |p| p.as_str())
+        .to_owned();
+    let method = req.method().to_string();
+    let start = Instant::now();
+    let response = next.run(req).await;
+    let seconds = start.elapsed().as_secs_f64();
+    let mut metrics = metrics();
+    let status = response.status().as_u16();
+    *metrics
+        .requests
+        .entry((route.clone(), method, status))
+        .or_default() += 1;
+    metrics
+        .request_duration
+        .entry(route)
+        .or_insert_with(Histogram::new)
+        .observe(seconds);
+    response
+}
+
+/// Counts taken from the content of the wiki.
+#[derive(Clone)]
+pub struct RepoStats {
+    pages: usize,
+    /// Commits by author name.
+    commits: BTreeMap<String, u64>,
+}
+
+impl RepoStats {
+    pub fn new(repo: &Repo) -> Result<RepoStats> {
+        let mut commits = BTreeMap::new();
+        for c in repo.get_log()? {
+            *commits.entry(c.author).or_default() += 1;
+        }
+        Ok(RepoStats {
+            // The home page isn't listed
+            pages: list_files(repo, "", true)?.len() + 1,
+            commits,
+        })
+    }
+}
+
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// All the metrics in the Prometheus text format.
+pub fn render(stats: Option<&RepoStats>) -> String {
+    let metrics = metrics();
+    let mut out = String::new();
+
+    out.push_str(
+        "# HELP wikimark_http_requests_total HTTP requests by route, method and status.\n",
+    );
+    out.push_str("# TYPE wikimark_http_requests_total counter\n");
+    for ((route, method, status), count) in &metrics.requests {
+        let route = label(route);
+        let _ = writeln!(
+            out,
+            "wikimark_http_requests_total{{route=\"{route}\",method=\"{method}\",status=\"{status}\"}} {count}"
+        );
+    }
+    out.push_str("# HELP wikimark_http_request_duration_seconds HTTP request latency by route.\n");
+    out.push_str("# TYPE wikimark_http_request_duration_seconds histogram\n");
+    for (route, h) in &metrics.request_duration {
+        let labels = format!("route=\"{}\"", label(route));
+        h.write(&mut out, "wikimark_http_request_duration_seconds", &labels);
+    }
+    out.push_str("# HELP wikimark_render_duration_seconds Time to render a page to HTML.\n");
+    out.push_str("# TYPE wikimark_render_duration_seconds histogram\n");
+    metrics
+        .render_duration
+        .write(&mut out, "wikimark_render_duration_seconds", "");
+    out.push_str("# HELP wikimark_git_duration_seconds Latency of git operations.\n");
+    out.push_str("# TYPE wikimark_git_duration_seconds histogram\n");
+    for (op, h) in &metrics.git_duration {
+        let labels = format!("operation=\"{op}\"");
+        h.write(&mut out, "wikimark_git_duration_seconds", &labels);
+    }
+
+    // An empty repo has no pages nor commits
+    let stats = stats.cloned().unwrap_or(RepoStats {
+        pages: 0,
+        commits: BTreeMap::new(),
+    });
+    out.push_str("# HELP wikimark_pages Pages of the wiki, including private ones.\n");
+    out.push_str("# TYPE wikimark_pages gauge\n");
+    let _ = writeln!(out, "wikimark_pages {}", stats.pages);
+    out.push_str("# HELP wikimark_commits Commits on master by author.\n");
+    out.push_str("# TYPE wikimark_commits gauge\n");
+    for (author, count) in &stats.commits {
+        let _ = writeln!(
+            out,
+            "wikimark_commits{{author=\"{}\"}} {count}",
+            label(author)
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram() {
+        let mut h = Histogram::new();
+        h.observe(0.003);
+        h.observe(2.0);
+        let mut out = String::new();
+        h.write(&mut out, "t", "op=\"a\"");
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines[0], "t_bucket{op=\"a\",le=\"0.001\"} 0");
+        assert_eq!(lines[2], "t_bucket{op=\"a\",le=\"0.005\"} 1");
+        assert_eq!(lines[9], "t_bucket{op=\"a\",le=\"5\"} 2");
+        assert_eq!(lines[10], "t_bucket{op=\"a\",le=\"+Inf\"} 2");
+        assert_eq!(lines[11], "t_sum{op=\"a\"} 2.003");
+        assert_eq!(lines[12], "t_count{op=\"a\"} 2");
+        assert_eq!(label("a \"b\"\n"), "a \\\"b\\\"\\n");
+    }
+}
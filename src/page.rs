@@ -1,8 +1,10 @@
+use pulldown_cmark::{Event, HeadingLevel, Parser, Tag, TagEnd};
 use serde_derive::{Deserialize, Serialize};
 use serde_yaml::Value;
 use slab_tree::{RemoveBehavior, Tree};
 use slug::slugify;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
 
 use crate::git::{CommitData, EntryKind, Repo};
 
@@ -40,15 +42,61 @@ impl Toc {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
 pub struct Metadata {
+    #[serde(default)]
     pub title: String,
     #[serde(default)]
     pub private: bool,
+    #[serde(default, deserialize_with = "one_or_many", skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
     #[serde(flatten)]
     pub other: BTreeMap<String, Value>,
 }
 
+/// Tags given as a list or as a single value. Numbers and booleans are
+/// tags too, written as strings.
+fn one_or_many<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+    use serde::Deserialize;
+    fn scalar(v: Value) -> Option<String> {
+        match v {
+            Value::String(s) => Some(s),
+            Value::Number(n) => Some(n.to_string()),
+            Value::Bool(b) => Some(b.to_string()),
+            _ => None,
+        }
+    }
+    let tags = match Option::<Value>::deserialize(deserializer)? {
+        None | Some(Value::Null) => Some(vec![]),
+        Some(Value::Sequence(s)) => s.into_iter().map(scalar).collect(),
+        Some(v) => scalar(v).map(|t| vec![t]),
+    };
+    tags.ok_or_else(|| D::Error::custom("tags must be a list of strings"))
+}
+
+impl Metadata {
+    /// All front matter fields as a single map, including `title`.
+    pub fn fields(&self) -> BTreeMap<String, Value> {
+        serde_yaml::to_value(self)
+            .and_then(serde_yaml::from_value)
+            .unwrap_or_default()
+    }
+
+    /// Other paths under which the page can be found, from `aliases`.
+    pub fn aliases(&self) -> impl Iterator<Item = &str> {
+        let aliases = match self.other.get("aliases") {
+            Some(Value::Sequence(s)) => s.as_slice(),
+            Some(v) => std::slice::from_ref(v),
+            None => &[],
+        };
+        aliases.iter().filter_map(Value::as_str)
+    }
+}
+
 pub struct Page {
     pub toc: Toc,
     pub content: String,
@@ -65,36 +113,217 @@ pub struct Section {
 pub struct RawPage {
     pub meta: Metadata,
     pub content: String,
+    /// Set if the front matter is broken and `meta` is only a placeholder.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<FrontMatterError>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PageEntry {
     pub meta: Metadata,
     pub link: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<FrontMatterError>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FrontMatterError {
+    Invalid {
+        message: String,
+        line: Option<usize>,
+        column: Option<usize>,
+    },
+}
+
+impl std::fmt::Display for FrontMatterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrontMatterError::Invalid { message, .. } => {
+                write!(f, "invalid front matter: {message}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FrontMatterError {}
+
+/// A new page would overwrite the existing page at the given path.
+#[derive(Debug)]
+pub struct PageExists(pub String);
+
+impl std::fmt::Display for PageExists {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "a page already exists at `{}`", self.0)
+    }
 }
 
+impl std::error::Error for PageExists {}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PageUpdate {
     pub page: RawPage,
+    /// Path of the page being edited, if it already exists.
+    pub original: Option<String>,
     pub parent: String,
     pub directory: bool,
 }
 
-pub fn parse_page(content: &str) -> Result<RawPage> {
-    if !content.starts_with("---") {
-        anyhow::bail!("missing YAML front matter");
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum FrontMatterFormat {
+    /// `---` delimited YAML
+    Yaml,
+    /// `+++` delimited TOML, as used by Zola and Hugo
+    Toml,
+    None,
+}
+
+/// Split a page into the format and text of its front matter and its body.
+/// The delimiters must be alone on their lines; the body starts right after
+/// the closing delimiter. Without a closing delimiter, the first line is a
+/// horizontal rule and the page has no front matter.
+fn split_front_matter(content: &str) -> (FrontMatterFormat, &str, &str) {
+    for (format, delimiter) in [
+        (FrontMatterFormat::Yaml, "---"),
+        (FrontMatterFormat::Toml, "+++"),
+    ] {
+        let Some(rest) = content
+            .strip_prefix(delimiter)
+            .and_then(|r| r.strip_prefix("\r\n").or_else(|| r.strip_prefix('\n')))
+        else {
+            continue;
+        };
+        let mut offset = 0;
+        for line in rest.split_inclusive('\n') {
+            if line.trim_end() == delimiter {
+                return (format, &rest[..offset], &rest[offset + delimiter.len()..]);
+            }
+            offset += line.len();
+        }
+    }
+    (FrontMatterFormat::None, "", content)
+}
+
+fn toml_to_yaml(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => Value::Number(i.into()),
+        toml::Value::Float(f) => Value::Number(f.into()),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(d) => Value::String(d.to_string()),
+        toml::Value::Array(a) => Value::Sequence(a.into_iter().map(toml_to_yaml).collect()),
+        toml::Value::Table(t) => Value::Mapping(
+            t.into_iter()
+                .map(|(k, v)| (Value::String(k), toml_to_yaml(v)))
+                .collect(),
+        ),
+    }
+}
+
+/// Text of the first level 1 heading, used as title by pages without one.
+fn first_heading(md: &str) -> Option<String> {
+    let mut title: Option<String> = None;
+    for event in Parser::new(md) {
+        match event {
+            Event::Start(Tag::Heading {
+                level: HeadingLevel::H1,
+                ..
+            }) => title = Some(String::new()),
+            Event::Text(t) | Event::Code(t) => {
+                if let Some(title) = &mut title {
+                    title.push_str(&t);
+                }
+            }
+            Event::End(TagEnd::Heading(HeadingLevel::H1)) => return title,
+            _ => {}
+        }
+    }
+    None
+}
+
+fn parse_metadata(
+    format: FrontMatterFormat,
+    front: &str,
+) -> std::result::Result<Metadata, FrontMatterError> {
+    if front.trim().is_empty() {
+        return Ok(Metadata::default());
+    }
+    // The front matter starts on the second line of the file
+    match format {
+        FrontMatterFormat::Yaml => {
+            serde_yaml::from_str(front).map_err(|e| FrontMatterError::Invalid {
+                message: e.to_string(),
+                line: e.location().map(|l| l.line() + 1),
+                column: e.location().map(|l| l.column()),
+            })
+        }
+        FrontMatterFormat::Toml => {
+            let table: toml::Table = toml::from_str(front).map_err(|e| {
+                let offset = e.span().map(|s| s.start);
+                FrontMatterError::Invalid {
+                    message: e.message().to_owned(),
+                    line: offset.map(|o| front[..o].matches('\n').count() + 2),
+                    column: offset.map(|o| o - front[..o].rfind('\n').map_or(0, |n| n + 1) + 1),
+                }
+            })?;
+            serde_yaml::from_value(toml_to_yaml(toml::Value::Table(table))).map_err(|e| {
+                FrontMatterError::Invalid {
+                    message: e.to_string(),
+                    line: None,
+                    column: None,
+                }
+            })
+        }
+        FrontMatterFormat::None => Ok(Metadata::default()),
+    }
+}
+
+/// Parse a page with YAML, TOML or no front matter. Pages without a title
+/// use their first heading, or `name` if they have none.
+pub fn parse_page(content: &str, name: &str) -> std::result::Result<RawPage, FrontMatterError> {
+    let (format, front, md) = split_front_matter(content);
+    let mut meta = parse_metadata(format, front)?;
+    if meta.title.is_empty() {
+        meta.title = first_heading(md).unwrap_or_else(|| name.to_owned());
     }
-    let yaml = content.trim_start_matches("---");
-    let (yaml, md) = yaml
-        .split_once("---")
-        .ok_or_else(|| anyhow::anyhow!("malformed YAML front matter"))?;
-    let meta = serde_yaml::from_str(yaml).expect("invalid YAML front matter");
     Ok(RawPage {
         meta,
         content: md.to_owned(),
+        error: None,
+    })
+}
+
+/// Whether `content` starts with a front matter block.
+pub fn has_front_matter(content: &str) -> bool {
+    split_front_matter(content).0 != FrontMatterFormat::None
+}
+
+/// Parse a page without failing: if the front matter is broken, the page
+/// gets `name` as title and the error is recorded in `RawPage::error`.
+/// Such pages are private, since the broken front matter may say so.
+pub fn read_page(content: &str, name: &str) -> RawPage {
+    parse_page(content, name).unwrap_or_else(|error| {
+        let (_, _, body) = split_front_matter(content);
+        RawPage {
+            meta: Metadata {
+                title: name.to_owned(),
+                private: true,
+                ..Default::default()
+            },
+            content: body.to_owned(),
+            error: Some(error),
+        }
     })
 }
 
+/// Name of the page at `path`, used as title when it has none.
+fn page_name(path: &str) -> &str {
+    match path.trim_end_matches('/').rsplit('/').next() {
+        Some("") | None => "Home",
+        Some(name) => name,
+    }
+}
+
 pub fn list_files(repo: &Repo, path: &str, recursive: bool) -> Result<Vec<PageEntry>> {
     let tree = repo.get_tree(path)?;
     let mut ret = vec![];
@@ -109,17 +338,21 @@ pub fn list_files(repo: &Repo, path: &str, recursive: bool) -> Result<Vec<PageEn
                     }
                     let name = &e.name[0..(e.name.len() - 3)];
                     let blob = repo.get_blob_from_id(e.id)?;
+                    let page = read_page(&String::from_utf8_lossy(&blob), name);
                     ret.push(PageEntry {
-                        meta: parse_page(std::str::from_utf8(&blob)?)?.meta,
+                        meta: page.meta,
                         link: format!("{prefix}{name}"),
+                        error: page.error,
                     });
                 }
                 EntryKind::Dir => {
-                    if let Ok(c) = repo.get_file(&format!("{path}{}/_index.md", e.name)) {
+                    if let Ok(c) = repo.get_file(&format!("{prefix}{}/_index.md", e.name)) {
                         let link = format!("{prefix}{}/", e.name);
+                        let page = read_page(&c, &e.name);
                         ret.push(PageEntry {
-                            meta: parse_page(&c)?.meta,
+                            meta: page.meta,
                             link: link.clone(),
+                            error: page.error,
                         });
                         if recursive {
                             stack.push((e.id, link));
@@ -133,27 +366,486 @@ pub fn list_files(repo: &Repo, path: &str, recursive: bool) -> Result<Vec<PageEn
     Ok(ret)
 }
 
-pub fn get_page(repo: &Repo, path: &str) -> Result<(RawPage, bool)> {
-    let (content, is_dir) = if path.ends_with('/') || path.is_empty() {
-        (repo.get_file(&format!("{}_index.md", path))?, true)
-    } else {
-        (repo.get_file(&format!("{}.md", path))?, false)
+/// Every tag used in the wiki, with the pages carrying it.
+pub fn list_tags(repo: &Repo) -> Result<BTreeMap<String, Vec<PageEntry>>> {
+    let mut tags: BTreeMap<String, Vec<PageEntry>> = BTreeMap::new();
+    for entry in list_files(repo, "", true)? {
+        for tag in &entry.meta.tags {
+            tags.entry(tag.clone()).or_default().push(entry.clone());
+        }
+    }
+    Ok(tags)
+}
+
+/// Type of a metadata field declared in a directory schema.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldKind {
+    #[default]
+    String,
+    Number,
+    Bool,
+    Date,
+    Enum,
+    List,
+}
+
+/// A metadata field that pages in a directory should have.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Field {
+    #[serde(default)]
+    pub name: String,
+    #[serde(rename = "type", default)]
+    pub kind: FieldKind,
+    #[serde(default)]
+    pub required: bool,
+    /// Allowed values of an `enum` field
+    #[serde(default)]
+    pub values: Vec<String>,
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+/// Metadata fields for the children of a directory, declared under
+/// `schema:` in its `_index.md`, e.g.
+///
+/// ```yaml
+/// schema:
+///   owner: { type: string, required: true }
+///   status: { type: enum, values: [draft, active, retired] }
+///   reviewed: date
+/// ```
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Schema(pub Vec<Field>);
+
+/// Metadata that doesn't match the schema of its directory.
+#[derive(Debug)]
+pub struct ValidationError(pub Vec<String>);
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid metadata: {}", self.0.join("; "))
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl Schema {
+    fn from_value(value: &Value) -> Result<Schema> {
+        let Value::Mapping(fields) = value else {
+            anyhow::bail!("schema must be a mapping of field names to types");
+        };
+        let mut schema = vec![];
+        for (name, spec) in fields {
+            let name = name
+                .as_str()
+                .ok_or_else(|| anyhow::anyhow!("schema field names must be strings"))?;
+            // `name: type` is a shorthand for `name: { type: type }`
+            let spec = match spec {
+                Value::String(kind) => Value::Mapping(
+                    [("type".into(), kind.as_str().into())]
+                        .into_iter()
+                        .collect(),
+                ),
+                spec => spec.clone(),
+            };
+            let mut field: Field = serde_yaml::from_value(spec)
+                .map_err(|e| anyhow::anyhow!("schema field `{name}`: {e}"))?;
+            field.name = name.to_owned();
+            schema.push(field);
+        }
+        Ok(Schema(schema))
+    }
+
+    /// Convert the string values submitted by the edit form to the types
+    /// declared by the schema, checking that they are valid. Empty fields
+    /// become `null`. An unchecked box isn't submitted, so a missing bool
+    /// field is `false` only if it is in the `checkboxes` the form showed.
+    pub fn validate(
+        &self,
+        fields: &mut BTreeMap<String, Value>,
+        checkboxes: &[&str],
+    ) -> std::result::Result<(), ValidationError> {
+        let mut errors = vec![];
+        for field in &self.0 {
+            let raw = match fields.get(&field.name) {
+                Some(Value::String(s)) => s.trim().to_owned(),
+                Some(Value::Null) => String::new(),
+                None if field.kind == FieldKind::Bool
+                    && !checkboxes.contains(&field.name.as_str()) =>
+                {
+                    continue;
+                }
+                None => String::new(),
+                Some(v) => display_value(v),
+            };
+            let value = if field.kind == FieldKind::Bool {
+                Value::Bool(raw == "true")
+            } else if raw.is_empty() {
+                if field.required {
+                    errors.push(format!("`{}` is required", field.name));
+                }
+                Value::Null
+            } else {
+                match field.kind {
+                    FieldKind::Number => match raw.parse::<i64>() {
+                        Ok(i) => Value::from(i),
+                        Err(_) => match raw.parse::<f64>() {
+                            Ok(f) => Value::from(f),
+                            Err(_) => {
+                                errors.push(format!("`{}` must be a number", field.name));
+                                Value::Null
+                            }
+                        },
+                    },
+                    FieldKind::Date => {
+                        if chrono::NaiveDate::parse_from_str(&raw, "%Y-%m-%d").is_err() {
+                            errors.push(format!("`{}` must be a YYYY-MM-DD date", field.name));
+                        }
+                        Value::String(raw)
+                    }
+                    FieldKind::Enum => {
+                        if !field.values.contains(&raw) {
+                            errors.push(format!(
+                                "`{}` must be one of {}",
+                                field.name,
+                                field.values.join(", ")
+                            ));
+                        }
+                        Value::String(raw)
+                    }
+                    FieldKind::List => Value::Sequence(
+                        raw.split(',')
+                            .map(|v| v.trim())
+                            .filter(|v| !v.is_empty())
+                            .map(Value::from)
+                            .collect(),
+                    ),
+                    FieldKind::String | FieldKind::Bool => Value::String(raw),
+                }
+            };
+            fields.insert(field.name.clone(), value);
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationError(errors))
+        }
+    }
+}
+
+fn display_value(v: &Value) -> String {
+    match v {
+        Value::Sequence(s) => s.iter().map(display_value).collect::<Vec<_>>().join(", "),
+        Value::String(s) => s.clone(),
+        v => serde_yaml::to_string(v)
+            .unwrap_or_default()
+            .trim()
+            .to_owned(),
+    }
+}
+
+/// Schema for the children of the directory at `parent`.
+pub fn get_schema(repo: &Repo, parent: &str) -> Result<Schema> {
+    let mut parent = parent.trim_matches('/').to_owned();
+    if !parent.is_empty() {
+        parent.push('/');
+    }
+    let Ok(content) = repo.get_file(&format!("{parent}_index.md")) else {
+        return Ok(Schema::default());
     };
-    let page = parse_page(&content)?;
+    match read_page(&content, "").meta.other.get("schema") {
+        Some(schema) => Schema::from_value(schema),
+        None => Ok(Schema::default()),
+    }
+}
+
+/// Schema of a page in `parent`, where `original` is the path of the page
+/// if it already exists. The home page has none, as it isn't the child of
+/// any directory.
+pub fn page_schema(repo: &Repo, parent: &str, original: Option<&str>) -> Result<Schema> {
+    if original == Some("") {
+        return Ok(Schema::default());
+    }
+    get_schema(repo, parent)
+}
+
+/// File backing the page at `path`, and whether the page is a directory.
+fn page_file(path: &str) -> (String, bool) {
+    if path.ends_with('/') || path.is_empty() {
+        (format!("{}_index.md", path), true)
+    } else {
+        (format!("{}.md", path), false)
+    }
+}
+
+pub fn get_page(repo: &Repo, path: &str) -> Result<(RawPage, bool)> {
+    let (file, is_dir) = page_file(path);
+    let content = repo.get_file(&file)?;
+    let page = read_page(&content, page_name(path));
     Ok((page, is_dir))
 }
 
-fn write_page(p: &RawPage) -> Result<String> {
-    let mut ret = String::new();
-    ret.push_str("---\n");
+/// Up to five pages whose title or name look like the one of `path`, best
+/// matches first. Private pages are only returned if `private` is set.
+pub fn similar_pages(repo: &Repo, path: &str, private: bool) -> Result<Vec<PageEntry>> {
+    let name = page_name(path).to_lowercase();
+    let mut scored: Vec<_> = list_files(repo, "", true)?
+        .into_iter()
+        .filter(|e| private || !e.meta.private)
+        .map(|e| {
+            let title = strsim::normalized_levenshtein(&name, &e.meta.title.to_lowercase());
+            let file = strsim::normalized_levenshtein(&name, page_name(&e.link));
+            (title.max(file), e)
+        })
+        .filter(|(score, _)| *score >= 0.5)
+        .collect();
+    scored.sort_by(|(x, _), (y, _)| y.total_cmp(x));
+    Ok(scored.into_iter().take(5).map(|(_, e)| e).collect())
+}
+
+/// File of the wiki repo mapping old page paths to new ones.
+pub const REDIRECTS_FILE: &str = "redirects.yaml";
+
+/// Page path of an internal link, without the `/page/` prefix.
+fn normalize_link(link: &str) -> &str {
+    let link = link.trim_start_matches('/');
+    link.strip_prefix("page/").unwrap_or(link)
+}
+
+/// Target of `path` according to the redirect `rules`. A rule whose source
+/// ends with `/` also moves every page below it.
+fn resolve_redirect(rules: &BTreeMap<String, String>, path: &str) -> Option<String> {
+    let trimmed = path.trim_end_matches('/');
+    let exact = rules
+        .iter()
+        .find(|(from, _)| normalize_link(from).trim_end_matches('/') == trimmed)
+        .map(|(_, to)| normalize_link(to).to_owned());
+    let target = exact.or_else(|| {
+        rules
+            .iter()
+            .filter_map(|(from, to)| {
+                let from = normalize_link(from);
+                let rest = path.strip_prefix(from).filter(|_| from.ends_with('/'))?;
+                Some((from.len(), to, rest))
+            })
+            .max_by_key(|(len, ..)| *len)
+            .map(|(_, to, rest)| match normalize_link(to).trim_end_matches('/') {
+                "" => rest.to_owned(),
+                to => format!("{to}/{rest}"),
+            })
+    })?;
+    (target != path).then_some(target)
+}
+
+/// Where a request for the missing page at `path` should go, from
+/// `redirects.yaml` or the `aliases` of the existing pages.
+pub fn find_redirect(repo: &Repo, path: &str) -> Result<Option<String>> {
+    if let Ok(content) = repo.get_file(REDIRECTS_FILE) {
+        let rules: BTreeMap<String, String> = serde_yaml::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("invalid {REDIRECTS_FILE}: {e}"))?;
+        if let Some(target) = resolve_redirect(&rules, path) {
+            return Ok(Some(target));
+        }
+    }
+    let path = path.trim_end_matches('/');
+    Ok(list_files(repo, "", true)?
+        .into_iter()
+        .find(|e| {
+            e.meta
+                .aliases()
+                .any(|a| normalize_link(a).trim_end_matches('/') == path)
+        })
+        .map(|e| e.link))
+}
+
+/// Directory of the wiki repo holding page templates.
+const TEMPLATES_DIR: &str = "page-templates";
+
+/// Names of the page templates, without the `.md` extension.
+pub fn list_templates(repo: &Repo) -> Result<Vec<String>> {
+    let Ok(tree) = repo.get_tree(TEMPLATES_DIR) else {
+        return Ok(vec![]);
+    };
+    let mut names: Vec<_> = Repo::list_entries(&tree)?
+        .filter(|e| matches!(e.kind, EntryKind::File))
+        .filter_map(|e| e.name.strip_suffix(".md").map(str::to_owned))
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// Load the page template `name`, replacing each `{{key}}` placeholder with
+/// its value in `vars`.
+pub fn get_template(repo: &Repo, name: &str, vars: &[(&str, &str)]) -> Result<RawPage> {
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        anyhow::bail!("invalid template name `{name}`");
+    }
+    let mut content = repo.get_file(&format!("{TEMPLATES_DIR}/{name}.md"))?;
+    for (key, value) in vars {
+        content = content
+            .replace(&format!("{{{{{key}}}}}"), value)
+            .replace(&format!("{{{{ {key} }}}}"), value);
+    }
+    Ok(read_page(&content, ""))
+}
+
+/// Name of the top-level entry defined on `line`, if any.
+fn front_matter_key(format: FrontMatterFormat, line: &str) -> Option<String> {
+    if line.starts_with(char::is_whitespace) || line.starts_with('#') {
+        return None;
+    }
+    let (key, _) = match format {
+        FrontMatterFormat::Toml => line.split_once('=')?,
+        _ => line.split_once(':')?,
+    };
+    let key = key.trim();
+    let key = key
+        .strip_prefix('"')
+        .and_then(|k| k.strip_suffix('"'))
+        .or_else(|| key.strip_prefix('\'').and_then(|k| k.strip_suffix('\'')))
+        .unwrap_or(key);
+    Some(key.to_owned())
+}
+
+/// Whether `line` continues the value of the entry above it.
+fn is_continuation(format: FrontMatterFormat, line: &str) -> bool {
+    match format {
+        FrontMatterFormat::Toml => line.starts_with([' ', '\t', ']', '}']),
+        _ => line.starts_with([' ', '\t', '-']),
+    }
+}
+
+/// Line ranges of the top-level entries of a front matter, and the line
+/// where new entries go (before the first table for TOML).
+fn front_matter_entries(
+    format: FrontMatterFormat,
+    lines: &[&str],
+) -> (Vec<(String, Range<usize>)>, usize) {
+    let mut entries = vec![];
+    let mut i = 0;
+    while i < lines.len() {
+        if format == FrontMatterFormat::Toml && lines[i].starts_with('[') {
+            break;
+        }
+        let Some(key) = front_matter_key(format, lines[i]) else {
+            i += 1;
+            continue;
+        };
+        let start = i;
+        i += 1;
+        let mut end = i;
+        while i < lines.len() {
+            if is_continuation(format, lines[i]) {
+                i += 1;
+                end = i;
+            } else if lines[i].trim().is_empty() {
+                i += 1;
+            } else {
+                break;
+            }
+        }
+        i = end;
+        entries.push((key, start..end));
+    }
+    while i > 0 && lines[i - 1].trim().is_empty() {
+        i -= 1;
+    }
+    (entries, i)
+}
+
+fn serialize_entry(format: FrontMatterFormat, key: &str, value: &Value) -> Option<String> {
+    let entry = BTreeMap::from([(key, value)]);
+    match format {
+        // A table would end up in the wrong place
+        FrontMatterFormat::Toml => toml::to_string(&entry)
+            .ok()
+            .filter(|s| !s.lines().any(|l| l.starts_with('['))),
+        _ => serde_yaml::to_string(&entry).ok(),
+    }
+}
+
+/// Edit the `front` matter of a page so that it describes `new` instead of
+/// `old`, rewriting only the entries that changed and keeping everything
+/// else (order, comments, formatting) as it is. Returns `None` if that
+/// can't be done in place.
+fn update_front_matter(
+    format: FrontMatterFormat,
+    front: &str,
+    old: &Metadata,
+    new: &Metadata,
+) -> Option<String> {
+    let (old, new) = (old.fields(), new.fields());
+    let lines: Vec<&str> = front.split_inclusive('\n').collect();
+    let (entries, insert_at) = front_matter_entries(format, &lines);
+    let mut edits = vec![];
+    let mut added = String::new();
+    let keys: BTreeSet<_> = old.keys().chain(new.keys()).collect();
+    for key in keys {
+        let value = new.get(key);
+        if old.get(key) == value {
+            continue;
+        }
+        let text = match value {
+            Some(v) => serialize_entry(format, key, v)?,
+            None => String::new(),
+        };
+        match entries.iter().find(|(k, _)| k == key) {
+            Some((_, range)) => edits.push((range.clone(), text)),
+            None => added.push_str(&text),
+        }
+    }
+    edits.push((insert_at..insert_at, added));
+    // Apply from the bottom so that the other ranges stay valid
+    edits.sort_by_key(|(range, _)| std::cmp::Reverse(range.start));
+    let mut lines: Vec<String> = lines.into_iter().map(str::to_owned).collect();
+    for (range, text) in edits {
+        lines.splice(range, (!text.is_empty()).then_some(text));
+    }
+    Some(lines.concat())
+}
+
+/// Serialize a page. If `original` is the current content of the page, its
+/// front matter is edited in place instead of being regenerated.
+pub fn write_page(p: &RawPage, original: Option<(&str, &str)>) -> Result<String> {
+    if let Some((content, name)) = original
+        && let Ok(old) = parse_page(content, name)
+    {
+        let (format, front, _) = split_front_matter(content);
+        match update_front_matter(format, front, &old.meta, &p.meta) {
+            Some(front) if format == FrontMatterFormat::None && front.is_empty() => {
+                return Ok(p.content.clone());
+            }
+            Some(front) if format == FrontMatterFormat::Toml => {
+                return Ok(format!("+++\n{front}+++\n{}", p.content));
+            }
+            Some(front) => return Ok(format!("---\n{front}---\n{}", p.content)),
+            None if format == FrontMatterFormat::Toml => {
+                let toml = toml::to_string(&p.meta)?;
+                return Ok(format!("+++\n{toml}+++\n{}", p.content));
+            }
+            None => {}
+        }
+    }
     let yaml = serde_yaml::to_string(&p.meta)?;
-    ret.push_str(&yaml);
-    ret.push_str("\n---\n");
-    ret.push_str(&p.content);
-    Ok(ret)
+    Ok(format!("---\n{yaml}---\n{}", p.content))
 }
 
-pub fn commit_page(repo: &Repo, author: String, update: PageUpdate) -> Result<String> {
+pub fn commit_page(repo: &Repo, author: String, mut update: PageUpdate) -> Result<String> {
+    let original = update.original.as_deref().and_then(|path| {
+        let content = repo.get_file(&page_file(path).0).ok()?;
+        Some((content, page_name(path)))
+    });
+    // Fields that the form doesn't know about are kept as they are
+    if let Some((content, name)) = &original
+        && let Ok(old) = parse_page(content, name)
+    {
+        for (k, v) in old.meta.other {
+            update.page.meta.other.entry(k).or_insert(v);
+        }
+    }
+    // Fields cleared in the form
+    update.page.meta.other.retain(|_, v| !v.is_null());
     let fname = slugify(&update.page.meta.title);
     let mut parent = update.parent;
     if !parent.ends_with('/') && !parent.is_empty() {
@@ -161,19 +853,243 @@ pub fn commit_page(repo: &Repo, author: String, update: PageUpdate) -> Result<St
     }
     let (path, link) = if update.directory {
         let link = format!("{parent}{fname}/");
-        (format!("{link}/_index.md"), link)
+        (format!("{link}_index.md"), link)
     } else {
         let link = format!("{parent}{fname}");
         (format!("{link}.md"), link)
     };
-    let content = write_page(&update.page)?;
+    // Only the page being edited may be overwritten
+    let moved = update.original.as_deref().map(|o| page_file(o).0).as_ref() != Some(&path);
+    if moved && repo.get_file(&path).is_ok() {
+        return Err(PageExists(link).into());
+    }
+    let content = write_page(
+        &update.page,
+        original.as_ref().map(|(c, name)| (c.as_str(), *name)),
+    )?;
 
     let data = CommitData {
         author,
         removed: vec![],
         added: vec![(path, content)],
         msg: format!("Edited `{}` from web", update.page.meta.title),
+        timestamp: None,
     };
     repo.commit(&data)?;
     Ok(link)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirect_rules() {
+        let rules = BTreeMap::from([
+            ("old".to_owned(), "new".to_owned()),
+            ("/page/team/".to_owned(), "teams/core/".to_owned()),
+            ("team/infra/".to_owned(), "infra/".to_owned()),
+            ("loop".to_owned(), "loop".to_owned()),
+        ]);
+        let target = |p| resolve_redirect(&rules, p);
+        assert_eq!(target("old").as_deref(), Some("new"));
+        assert_eq!(target("team").as_deref(), Some("teams/core/"));
+        assert_eq!(target("team/a").as_deref(), Some("teams/core/a"));
+        assert_eq!(target("team/infra/b/").as_deref(), Some("infra/b/"));
+        assert_eq!(target("loop"), None);
+        assert_eq!(target("other"), None);
+    }
+
+    #[test]
+    fn yaml_front_matter() {
+        let page = parse_page("---\ntitle: Hello\ntags: [a]\n---\nbody\n", "x").unwrap();
+        assert_eq!(page.meta.title, "Hello");
+        assert_eq!(page.meta.tags, ["a"]);
+        assert_eq!(page.content, "\nbody\n");
+        let page = parse_page("---\ntitle: \"a --- b\"\n---\nbody\n", "x").unwrap();
+        assert_eq!(page.meta.title, "a --- b");
+        let page = parse_page("---\ntitle: Ops\ntags: ops\n---\n", "x").unwrap();
+        assert_eq!(page.meta.title, "Ops");
+        assert_eq!(page.meta.tags, ["ops"]);
+        let page = parse_page("---\ntags:\n---\n", "x").unwrap();
+        assert!(page.meta.tags.is_empty());
+        let page = parse_page("---\ntags: [2024, ops, true]\n---\n", "x").unwrap();
+        assert_eq!(page.meta.tags, ["2024", "ops", "true"]);
+        let page = parse_page("---\ntags: 2024\n---\n", "x").unwrap();
+        assert_eq!(page.meta.tags, ["2024"]);
+        assert!(parse_page("---\ntags: {a: b}\n---\n", "x").is_err());
+    }
+
+    #[test]
+    fn toml_front_matter() {
+        let page = parse_page(
+            "+++\ntitle = \"Hello\"\ndate = 2024-01-02\n[extra]\nk = 1\n+++\nbody\n",
+            "x",
+        )
+        .unwrap();
+        assert_eq!(page.meta.title, "Hello");
+        assert_eq!(page.meta.other["date"], Value::from("2024-01-02"));
+        assert_eq!(page.meta.other["extra"]["k"], Value::from(1));
+        let page = parse_page("+++\ntags = [2024, \"ops\"]\n+++\n", "x").unwrap();
+        assert_eq!(page.meta.tags, ["2024", "ops"]);
+        let page = parse_page("+++\ntags = 2024\n+++\n", "x").unwrap();
+        assert_eq!(page.meta.tags, ["2024"]);
+    }
+
+    #[test]
+    fn title_without_front_matter() {
+        let page = parse_page("Intro\n\n# The `Title`\n", "file").unwrap();
+        assert_eq!(page.meta.title, "The Title");
+        assert_eq!(page.content, "Intro\n\n# The `Title`\n");
+        assert_eq!(
+            parse_page("no heading\n", "file").unwrap().meta.title,
+            "file"
+        );
+        let page = parse_page("---\n\n# Notes\nbody\n", "file").unwrap();
+        assert_eq!(page.meta.title, "Notes");
+        assert_eq!(page.content, "---\n\n# Notes\nbody\n");
+    }
+
+    #[test]
+    fn invalid_front_matter_location() {
+        let err = parse_page("+++\ntitle = \"a\"\nbad =\n+++\n", "x").unwrap_err();
+        assert!(matches!(
+            err,
+            FrontMatterError::Invalid { line: Some(3), .. }
+        ));
+        let page = read_page("---\ntitle: [\n---\nbody", "name");
+        assert_eq!(page.meta.title, "name");
+        assert_eq!(page.content, "\nbody");
+        assert!(page.error.is_some());
+    }
+
+    #[test]
+    fn broken_front_matter_is_private() {
+        let page = read_page("---\nprivate: true\ntags: [a, {\n---\nsecret\n", "secret");
+        assert!(page.error.is_some());
+        assert!(page.meta.private);
+    }
+
+    fn schema(yaml: &str) -> Schema {
+        Schema::from_value(&serde_yaml::from_str(yaml).unwrap()).unwrap()
+    }
+
+    fn form(fields: &[(&str, &str)]) -> BTreeMap<String, Value> {
+        fields
+            .iter()
+            .map(|(k, v)| (k.to_string(), Value::from(*v)))
+            .collect()
+    }
+
+    #[test]
+    fn schema_fields() {
+        let s = schema("owner: { type: string, required: true, label: Owner }
+reviewed: date
+");
+        assert_eq!(s.0[0].name, "owner");
+        assert!(s.0[0].required);
+        assert_eq!(s.0[0].label.as_deref(), Some("Owner"));
+        assert_eq!(s.0[1].kind, FieldKind::Date);
+        assert!(!s.0[1].required);
+        assert!(Schema::from_value(&Value::from("date")).is_err());
+        assert!(Schema::from_value(&serde_yaml::from_str("x: color").unwrap()).is_err());
+    }
+
+    #[test]
+    fn schema_validation() {
+        let s = schema(concat!(
+            "owner: { type: string, required: true }
+",
+            "status: { type: enum, values: [draft, active] }
+",
+            "size: number
+",
+            "reviewed: date
+",
+            "teams: list
+",
+            "archived: bool
+",
+        ));
+        let mut fields = form(&[
+            ("owner", " ann "),
+            ("status", "draft"),
+            ("size", "2.5"),
+            ("reviewed", "2024-02-29"),
+            ("teams", "a, b,,"),
+            ("archived", "true"),
+        ]);
+        s.validate(&mut fields, &[]).unwrap();
+        assert_eq!(fields["owner"], Value::from("ann"));
+        assert_eq!(fields["size"], Value::from(2.5));
+        assert_eq!(fields["reviewed"], Value::from("2024-02-29"));
+        assert_eq!(fields["teams"], serde_yaml::from_str::<Value>("[a, b]").unwrap());
+        assert_eq!(fields["archived"], Value::Bool(true));
+
+        let mut fields = form(&[("size", "7"), ("reviewed", "")]);
+        s.validate(&mut fields, &[]).unwrap_err();
+        assert_eq!(fields["size"], Value::from(7));
+        assert_eq!(fields["reviewed"], Value::Null);
+        // Not shown, the page keeps its value
+        assert!(!fields.contains_key("archived"));
+        let mut fields = form(&[("owner", "ann")]);
+        s.validate(&mut fields, &["archived"]).unwrap();
+        assert_eq!(fields["archived"], Value::Bool(false));
+
+        let mut fields = form(&[
+            ("status", "gone"),
+            ("size", "big"),
+            ("reviewed", "2024-02-30"),
+        ]);
+        let err = s.validate(&mut fields, &[]).unwrap_err();
+        assert_eq!(
+            err.0,
+            [
+                "`owner` is required",
+                "`status` must be one of draft, active",
+                "`size` must be a number",
+                "`reviewed` must be a YYYY-MM-DD date",
+            ]
+        );
+    }
+
+    fn edit(original: &str, f: impl FnOnce(&mut Metadata)) -> String {
+        let mut page = parse_page(original, "name").unwrap();
+        f(&mut page.meta);
+        page.content = page.content.trim_start_matches('\n').to_owned();
+        write_page(&page, Some((original, "name"))).unwrap()
+    }
+
+    #[test]
+    fn edits_keep_front_matter_layout() {
+        let original = "---\n# comment\ntitle: Old\nowner: x  # inline\ntags:\n- a\n---\nbody\n";
+        assert_eq!(edit(original, |_| {}), original);
+        assert_eq!(
+            edit(original, |m| {
+                m.title = "New".to_owned();
+                m.private = true;
+                m.tags.clear();
+            }),
+            "---\n# comment\ntitle: New\nowner: x  # inline\nprivate: true\n---\nbody\n"
+        );
+    }
+
+    #[test]
+    fn edits_keep_toml_front_matter() {
+        let original = "+++\ntitle = \"Old\"\n\n[extra]\nk = 1\n+++\nbody\n";
+        assert_eq!(
+            edit(original, |m| m.tags = vec!["t".to_owned()]),
+            "+++\ntitle = \"Old\"\ntags = [\"t\"]\n\n[extra]\nk = 1\n+++\nbody\n"
+        );
+    }
+
+    #[test]
+    fn edits_without_front_matter() {
+        let original = "# Title\nbody\n";
+        assert_eq!(edit(original, |_| {}), original);
+        assert_eq!(
+            edit(original, |m| m.private = true),
+            "---\nprivate: true\n---\n# Title\nbody\n"
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use serde_derive::Deserialize;
+use serde_yaml::Value;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use crate::git::Repo;
+use crate::md2html::{escape_href, escape_html};
+use crate::page::{list_files, PageEntry};
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+/// Body of a `wikiquery` fenced block, e.g.
+///
+/// ```yaml
+/// path: services/
+/// where:
+///   owner: payments
+/// sort: -updated
+/// columns: [title, owner, updated]
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Query {
+    #[serde(default)]
+    pub path: String,
+    #[serde(default = "default_recursive")]
+    pub recursive: bool,
+    /// Front matter fields that must match. A list field matches if it
+    /// contains the value.
+    #[serde(default, rename = "where")]
+    pub filter: BTreeMap<String, Value>,
+    /// Field to sort by, prefixed with `-` for descending order.
+    #[serde(default)]
+    pub sort: Option<String>,
+    #[serde(default = "default_columns")]
+    pub columns: Vec<String>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+fn default_recursive() -> bool {
+    true
+}
+
+fn default_columns() -> Vec<String> {
+    vec!["title".to_owned()]
+}
+
+fn display(v: &Value) -> String {
+    match v {
+        Value::Null => String::new(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => s.clone(),
+        Value::Sequence(s) => s.iter().map(display).collect::<Vec<_>>().join(", "),
+        Value::Tagged(t) => display(&t.value),
+        Value::Mapping(_) => serde_yaml::to_string(v)
+            .unwrap_or_default()
+            .trim()
+            .to_owned(),
+    }
+}
+
+fn matches(field: Option<&Value>, want: &Value) -> bool {
+    match (field, want) {
+        (None, Value::Null) => true,
+        (None, _) => false,
+        (Some(Value::Sequence(items)), Value::Sequence(wanted)) => wanted
+            .iter()
+            .all(|w| items.iter().any(|i| display(i) == display(w))),
+        (Some(Value::Sequence(items)), _) => items.iter().any(|i| display(i) == display(want)),
+        (Some(v), _) => display(v) == display(want),
+    }
+}
+
+fn compare(a: &Value, b: &Value) -> Ordering {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(x), Some(y)) => x.total_cmp(&y),
+        _ => display(a).cmp(&display(b)),
+    }
+}
+
+impl Query {
+    pub fn parse(src: &str) -> Result<Query> {
+        let mut query: Query = serde_yaml::from_str(src)?;
+        if !query.path.is_empty() && !query.path.ends_with('/') {
+            query.path.push('/');
+        }
+        Ok(query)
+    }
+
+    /// Pages matching the query, sorted and limited, with their front matter.
+    /// Private pages are only returned if `private` is set.
+    pub fn run(
+        &self,
+        repo: &Repo,
+        private: bool,
+    ) -> Result<Vec<(PageEntry, BTreeMap<String, Value>)>> {
+        let mut rows: Vec<_> = list_files(repo, &self.path, self.recursive)?
+            .into_iter()
+            .filter(|e| private || !e.meta.private)
+            .map(|e| {
+                let f = e.meta.fields();
+                (e, f)
+            })
+            .filter(|(_, f)| self.filter.iter().all(|(k, v)| matches(f.get(k), v)))
+            .collect();
+        if let Some(sort) = &self.sort {
+            let (key, desc) = match sort.strip_prefix('-') {
+                Some(key) => (key, true),
+                None => (sort.as_str(), false),
+            };
+            // Pages without the field always go last
+            rows.sort_by(|(_, x), (_, y)| match (x.get(key), y.get(key)) {
+                (Some(a), Some(b)) if desc => compare(b, a),
+                (Some(a), Some(b)) => compare(a, b),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            });
+        }
+        if let Some(limit) = self.limit {
+            rows.truncate(limit);
+        }
+        Ok(rows)
+    }
+
+    /// Render the query results as an HTML table. The `title` column links
+    /// to the page, under `base_path`.
+    pub fn render(&self, repo: &Repo, base_path: &str, private: bool) -> Result<String> {
+        let rows = self.run(repo, private)?;
+        let mut out = String::from("<table class=\"wikiquery\">\n<thead><tr>");
+        for c in &self.columns {
+            out.push_str(&format!("<th>{}</th>", escape_html(c)));
+        }
+        out.push_str("</tr></thead>\n<tbody>\n");
+        for (entry, fields) in &rows {
+            out.push_str("<tr>");
+            for c in &self.columns {
+                let cell = fields.get(c).map(display).unwrap_or_default();
+                if c == "title" {
+                    out.push_str(&format!(
+                        "<td><a href=\"{}/page/{}\">{}</a></td>",
+                        escape_href(base_path),
+                        escape_href(&entry.link),
+                        escape_html(&cell)
+                    ));
+                } else {
+                    out.push_str(&format!("<td>{}</td>", escape_html(&cell)));
+                }
+            }
+            out.push_str("</tr>\n");
+        }
+        if rows.is_empty() {
+            out.push_str(&format!(
+                "<tr><td colspan=\"{}\">No matching pages</td></tr>\n",
+                self.columns.len()
+            ));
+        }
+        out.push_str("</tbody>\n</table>\n");
+        Ok(out)
+    }
+}
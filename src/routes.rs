@@ -1,14 +1,16 @@
-use super::{errors, md2html, page, WikiState};
+use super::{check, errors::{self, AppError}, git::{CommitData, Repo}, journal, md2html, metrics, page, scss2css, WikiState};
 use axum::{
-    extract::{Path, State, Query, Form},
+    extract::{Path, State, Query, Form, Request},
+    http::{StatusCode, Uri},
+    middleware::Next,
     response::{Html, IntoResponse, Response, Redirect},
 };
 use axum_extra::TypedHeader;
 use axum_extra::headers::{Header, HeaderName, HeaderValue, Error as HeaderError};
 use serde_derive::Deserialize;
 use serde_yaml::Value;
-use minijinja::context;
-use std::sync::Arc;
+use minijinja::{context, Environment};
+use std::sync::{Arc, OnceLock};
 use std::collections::BTreeMap;
 
 type Result<T> = std::result::Result<T, errors::AppError>;
@@ -16,10 +18,19 @@ type Result<T> = std::result::Result<T, errors::AppError>;
 #[derive(Debug)]
 pub struct User(String);
 
-static USER_HEADER_NAME: HeaderName = HeaderName::from_static("remote-user");
+static USER_HEADER_NAME: OnceLock<HeaderName> = OnceLock::new();
+
+/// Use the header `name` for the name of the logged in user.
+pub fn set_user_header(name: &str) -> anyhow::Result<()> {
+    let name = HeaderName::from_bytes(name.as_bytes())?;
+    USER_HEADER_NAME
+        .set(name)
+        .map_err(|_| anyhow::anyhow!("user header already set"))
+}
+
 impl Header for User {
     fn name() -> &'static HeaderName {
-        &USER_HEADER_NAME
+        USER_HEADER_NAME.get_or_init(|| HeaderName::from_static("remote-user"))
     }
     fn decode<'i, I>(values: &mut I) -> std::result::Result<Self, HeaderError>
     where
@@ -74,42 +85,125 @@ impl<T> From<T> for Css<T> {
 
 type UserHeader = TypedHeader<User>;
 
-pub async fn index() -> impl IntoResponse {
-    Redirect::permanent("./page/")
+/// Name of the logged in user, needed to change the wiki.
+fn logged_in(user: Option<UserHeader>) -> Result<String> {
+    user.map(|u| u.0 .0)
+        .ok_or_else(|| AppError::Forbidden("You need to be logged in to edit the wiki".to_owned()))
+}
+
+/// Render error responses with the `error.html` template. Missing pages come
+/// with a link to create them and a list of pages with similar names.
+pub async fn render_error(
+    State(state): State<Arc<WikiState>>,
+    user: Option<UserHeader>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let is_page = req.uri().path().starts_with("/page/");
+    let response = next.run(req).await;
+    let Some(info) = response.extensions().get::<errors::ErrorInfo>().cloned() else {
+        return response;
+    };
+    let status = response.status();
+    let missing = info.missing.filter(|_| is_page && status == StatusCode::NOT_FOUND);
+    let private = user.is_some();
+    let user_str = user.as_ref().map(|u| u.0 .0.as_str());
+    let html = (|| -> anyhow::Result<String> {
+        let similar = match &missing {
+            Some(path) => page::similar_pages(&state.repo.local(), path, private)?,
+            None => vec![],
+        };
+        let parent = missing.as_deref().and_then(|p| p.trim_end_matches('/').rsplit_once('/'));
+        Ok(state.env.get_template("error.html")?.render(context!(
+            user => user_str,
+            status => status.as_u16(),
+            reason => status.canonical_reason(),
+            message => info.message,
+            missing => missing,
+            parent => parent.map(|p| p.0).unwrap_or_default(),
+            similar,
+        ))?)
+    })();
+    match html {
+        Ok(html) => (status, Html(html)).into_response(),
+        Err(e) => {
+            tracing::error!("can't render error page: {e:#}");
+            response
+        }
+    }
+}
+
+pub async fn not_found(uri: Uri) -> AppError {
+    AppError::NotFound(uri.path().to_owned())
+}
+
+pub async fn index(State(state): State<Arc<WikiState>>) -> impl IntoResponse {
+    Redirect::permanent(&format!("{}/page/", state.config.base_path))
 }
 
 pub async fn page(
     State(state): State<Arc<WikiState>>,
     user: Option<UserHeader>,
     fname: Option<Path<String>>,
-) -> Result<Html<String>> {
+) -> Result<Response> {
     let repo = state.repo.local();
     let fname = fname.unwrap_or_else(|| Path("".to_owned())).0;
-    let (md, directory) = page::get_page(&repo, &fname)?;
-    let templ_file = if directory { "dir.html" } else { "page.html" };
+    let (md, directory) = match page::get_page(&repo, &fname) {
+        Ok(page) => page,
+        Err(e) => match page::find_redirect(&repo, &fname)? {
+            Some(target) => {
+                let base = &state.config.base_path;
+                let location = HeaderValue::from_str(&format!("{base}/page/{target}"))?;
+                let redirect = [(http::header::LOCATION, location)];
+                return Ok((StatusCode::MOVED_PERMANENTLY, redirect).into_response());
+            }
+            None => match AppError::from(e) {
+                AppError::NotFound(_) => return Err(AppError::NotFound(fname)),
+                e => return Err(e),
+            },
+        },
+    };
+    if md.meta.private && user.is_none() {
+        return Err(AppError::Forbidden("This page is private".to_owned()));
+    }
     let entries = if directory {
         Some(page::list_files(&repo, &fname, false)?)
     } else {
         None
     };
-    let templ = state.env.get_template(templ_file).unwrap();
     let user_str = user.as_ref().map(|u| u.0 .0.as_str());
-    let page = md2html::parse(&md.content, &md.meta);
-    Ok(Html(templ.render(context!(
-        user => user_str,
+    Ok(Html(render_page(&state.env, &repo, user_str, &fname, md, entries)?).into_response())
+}
+
+/// Render the page at `link` with `page.html`, or `dir.html` if it has
+/// `children`.
+pub fn render_page(
+    env: &Environment,
+    repo: &Repo,
+    user: Option<&str>,
+    link: &str,
+    md: page::RawPage,
+    children: Option<Vec<page::PageEntry>>,
+) -> anyhow::Result<String> {
+    let templ_file = if children.is_some() { "dir.html" } else { "page.html" };
+    let templ = env.get_template(templ_file)?;
+    let page = md2html::parse(repo, env, link, &md.content, &md.meta);
+    Ok(templ.render(context!(
+        user,
         toc => page.toc,
         meta => md.meta,
         content => page.content,
-        link => fname,
-        children => entries,
-    ))?))
+        link,
+        children,
+        error => md.error.map(|e| e.to_string()),
+    ))?)
 }
 
 pub async fn pages(
     State(state): State<Arc<WikiState>>,
     user: Option<UserHeader>,
 ) -> Result<Html<String>> {
-    let templ = state.env.get_template("pages.html").unwrap();
+    let templ = state.env.get_template("pages.html")?;
     let user_str = user.as_ref().map(|u| u.0 .0.as_str());
     let pages = page::list_files(&state.repo.local(), "", true)?;
     Ok(Html(templ.render(context!(
@@ -118,41 +212,182 @@ pub async fn pages(
     ))?))
 }
 
+pub async fn tags(
+    State(state): State<Arc<WikiState>>,
+    user: Option<UserHeader>,
+) -> Result<Html<String>> {
+    let templ = state.env.get_template("tags.html")?;
+    let user_str = user.as_ref().map(|u| u.0 .0.as_str());
+    let tags: Vec<_> = page::list_tags(&state.repo.local())?
+        .into_iter()
+        .map(|(tag, pages)| (tag, pages.len()))
+        .collect();
+    Ok(Html(templ.render(context!(
+        user => user_str,
+        tags,
+    ))?))
+}
+
+pub async fn tag(
+    State(state): State<Arc<WikiState>>,
+    user: Option<UserHeader>,
+    Path(tag): Path<String>,
+) -> Result<Html<String>> {
+    let templ = state.env.get_template("tag.html")?;
+    let user_str = user.as_ref().map(|u| u.0 .0.as_str());
+    let pages = page::list_tags(&state.repo.local())?
+        .remove(&tag)
+        .unwrap_or_default();
+    Ok(Html(templ.render(context!(
+        user => user_str,
+        tag,
+        pages,
+    ))?))
+}
+
+/// Links of the wiki that don't lead anywhere.
+pub async fn broken_links(
+    State(state): State<Arc<WikiState>>,
+    user: Option<UserHeader>,
+) -> Result<Html<String>> {
+    let templ = state.env.get_template("check.html")?;
+    let user_str = user.as_ref().map(|u| u.0 .0.as_str());
+    let links = if user.is_some() {
+        check::check(&state.repo.local(), &state.env, &state.config.base_url)?
+    } else {
+        vec![]
+    };
+    Ok(Html(templ.render(context!(
+        user => user_str,
+        links,
+    ))?))
+}
+
 pub async fn changelog(
     State(state): State<Arc<WikiState>>,
     user: Option<UserHeader>,
 ) -> Result<Html<String>> {
-    let templ = state.env.get_template("changelog.html").unwrap();
+    let templ = state.env.get_template("changelog.html")?;
     let user_str = user.as_ref().map(|u| u.0 .0.as_str());
     Ok(Html(templ.render(context!(
         user => user_str,
         log => state.repo.local().get_log()?,
-        commit_url_prefix => state.commit_url_prefix,
+        commit_url_prefix => state.config.commit_url_prefix,
     ))?))
 }
 
+pub async fn journal(State(state): State<Arc<WikiState>>) -> impl IntoResponse {
+    let month = chrono::Local::now().format("%Y/%m");
+    Redirect::to(&format!("{}/journal/{month}", state.config.base_path))
+}
+
+pub async fn journal_month(
+    State(state): State<Arc<WikiState>>,
+    user: Option<UserHeader>,
+    Path((year, month)): Path<(i32, u32)>,
+) -> Result<Html<String>> {
+    let templ = state.env.get_template("journal.html")?;
+    let user_str = user.as_ref().map(|u| u.0 .0.as_str());
+    let dir = journal::journal_dir(&state.config.journal_dir, user_str.unwrap_or_default());
+    Ok(Html(templ.render(context!(
+        user => user_str,
+        month => journal::month(&state.repo.local(), &dir, year, month)?,
+    ))?))
+}
+
+pub async fn journal_today(
+    State(state): State<Arc<WikiState>>,
+    user: Option<UserHeader>,
+) -> Result<impl IntoResponse> {
+    let user = logged_in(user)?;
+    let repo = state.repo.local();
+    let now = chrono::Local::now();
+    let dir = journal::journal_dir(&state.config.journal_dir, &user);
+    let link = journal::entry_link(&dir, now.date_naive());
+    if page::get_page(&repo, &link).is_err() {
+        // The journal directory gets its page with the first entry
+        let added = journal::missing_dir_pages(&repo, &dir)?;
+        if !added.is_empty() {
+            repo.commit(&CommitData {
+                msg: format!("Created the journal `{dir}`"),
+                author: state.config.author(&user),
+                added,
+                removed: vec![],
+                timestamp: None,
+            })?;
+        }
+        let now = now.format("%Y-%m-%dT%H:%M:%S").to_string();
+        let mut entry = if page::list_templates(&repo)?.iter().any(|t| t == "journal") {
+            page::get_template(&repo, "journal", &template_vars(&user, &dir, &now))?
+        } else {
+            page::read_page("", "")
+        };
+        entry.meta.title = now[..10].to_owned();
+        let info = page::PageUpdate {
+            parent: dir,
+            directory: false,
+            original: None,
+            page: entry,
+        };
+        page::commit_page(&repo, state.config.author(&user), info)?;
+    }
+    Ok(Redirect::to(&format!("{}/page/{link}", state.config.base_path)))
+}
+
 #[derive(Deserialize)]
 pub struct EditQuery {
     page: Option<String>,
+    parent: Option<String>,
+    template: Option<String>,
+}
+
+/// Values for the placeholders of page templates.
+fn template_vars<'a>(user: &'a str, parent: &'a str, now: &'a str) -> [(&'a str, &'a str); 5] {
+    [
+        ("user", user),
+        ("parent", parent),
+        ("date", &now[..10]),
+        ("time", &now[11..16]),
+        ("datetime", now),
+    ]
 }
 
-pub async fn edit(State(state): State<Arc<WikiState>>, user: UserHeader, Query(q): Query<EditQuery>) -> Result<Html<String>> {
+pub async fn edit(State(state): State<Arc<WikiState>>, user: Option<UserHeader>, Query(q): Query<EditQuery>) -> Result<Html<String>> {
+    let user = logged_in(user)?;
     let repo = state.repo.local();
-    let user_str = user.0 .0.as_str();
-    let templ = state.env.get_template("edit.html").unwrap();
+    let user_str = user.as_str();
+    let templ = state.env.get_template("edit.html")?;
     if let Some(page) = q.page {
         let (md, directory) = page::get_page(&repo, &page)?;
-        let mut path = std::path::PathBuf::from(page);
+        let mut path = std::path::PathBuf::from(&page);
         path.pop();
+        let schema = page::page_schema(&repo, &path.to_string_lossy(), Some(&page))?;
         Ok(Html(templ.render(context!(
             user => user_str,
+            original => page,
+            error => md.error.as_ref().map(|e| e.to_string()),
             page => md,
             path => path,
             directory => directory,
+            schema => schema.0,
         ))?))
     } else {
+        let parent = q.parent.unwrap_or_default();
+        let parent = parent.trim_end_matches('/');
+        let schema = page::get_schema(&repo, parent)?;
+        let now = chrono::Local::now().format("%Y-%m-%dT%H:%M:%S").to_string();
+        let md = q
+            .template
+            .as_deref()
+            .map(|t| page::get_template(&repo, t, &template_vars(user_str, parent, &now)))
+            .transpose()?;
         Ok(Html(templ.render(context!(
             user => user_str,
+            path => parent,
+            schema => schema.0,
+            page => md,
+            template => q.template,
+            templates => page::list_templates(&repo)?,
         ))?))
     }
 }
@@ -166,32 +401,119 @@ pub struct CommitForm {
     private: bool,
     #[serde(default)]
     directory: bool,
+    #[serde(default)]
+    tags: String,
+    #[serde(default)]
+    original: Option<String>,
+    #[serde(default)]
+    template: Option<String>,
+    /// Schema checkboxes shown by the form, separated by commas.
+    #[serde(default)]
+    checkboxes: String,
     #[serde(flatten)]
     other: BTreeMap<String, Value>,
 }
 pub async fn commit(
     State(state): State<Arc<WikiState>>,
-    user: UserHeader,
-    Form(form): Form<CommitForm>,
+    user: Option<UserHeader>,
+    Form(mut form): Form<CommitForm>,
 ) -> Result<impl IntoResponse> {
+    let user = logged_in(user)?;
+    let repo = state.repo.local();
+    let checkboxes: Vec<_> = form.checkboxes.split(',').collect();
+    page::page_schema(&repo, &form.parent, form.original.as_deref())?
+        .validate(&mut form.other, &checkboxes)?;
+    // Keep the template front matter that isn't part of the form
+    if let Some(t) = &form.template {
+        let now = chrono::Local::now().format("%Y-%m-%dT%H:%M:%S").to_string();
+        let vars = template_vars(&user, &form.parent, &now);
+        for (k, v) in page::get_template(&repo, t, &vars)?.meta.other {
+            form.other.entry(k).or_insert(v);
+        }
+    }
     let info = page::PageUpdate {
         parent: form.parent,
         directory: form.directory,
+        original: form.original,
         page: page::RawPage {
-            content: form.content,
+            // Browsers submit CRLF line endings
+            content: form.content.replace("\r\n", "\n"),
+            error: None,
             meta: page::Metadata {
                 title: form.title,
                 private: form.private,
+                tags: form
+                    .tags
+                    .split(',')
+                    .map(|t| t.trim().to_owned())
+                    .filter(|t| !t.is_empty())
+                    .collect(),
                 other: form.other,
             },
         }
     };
-    let ret = page::commit_page(&state.repo.local(), user.0 .0, info)?;
-    Ok(Redirect::to(&format!("./page/{ret}")))
+    let ret = page::commit_page(&repo, state.config.author(&user), info)?;
+    Ok(Redirect::to(&format!("{}/page/{ret}", state.config.base_path)))
 }
 
-pub async fn css() -> Css<String> {
-    Css(super::CSS.to_owned())
+pub async fn css(State(state): State<Arc<WikiState>>) -> Result<Css<String>> {
+    let repo = state.repo.local();
+    // An empty repo only has the built-in styles
+    let Ok(head) = repo.head_id() else {
+        return Ok(Css(super::CSS.iter().map(|(_, css)| *css).collect()));
+    };
+    let mut cache = state.css.lock().unwrap();
+    if let Some((id, css)) = &*cache
+        && *id == head
+    {
+        return Ok(Css(css.clone()));
+    }
+    let css = match scss2css::site_css(&repo, &super::CSS) {
+        Ok(css) => css,
+        // Not cached, the next request tries again
+        Err(e) => {
+            tracing::error!("cannot read the styles of the repo: {e:#}");
+            return Ok(Css(super::CSS.iter().map(|(_, css)| *css).collect()));
+        }
+    };
+    *cache = Some((head, css.clone()));
+    Ok(Css(css))
+}
+
+/// Whether `master` can be resolved in the repo the wiki serves.
+pub async fn healthz(State(state): State<Arc<WikiState>>) -> Response {
+    match state.repo.local().head_id() {
+        Ok(_) => "ok\n".into_response(),
+        Err(e) => {
+            tracing::warn!("health check failed: {e:#}");
+            (StatusCode::SERVICE_UNAVAILABLE, format!("{e}\n")).into_response()
+        }
+    }
+}
+
+/// Metrics in the Prometheus text format.
+pub async fn metrics(State(state): State<Arc<WikiState>>) -> Result<Response> {
+    let repo = state.repo.local();
+    let stats = match repo.head_id() {
+        Ok(head) => {
+            let mut cache = state.stats.lock().unwrap();
+            match &*cache {
+                Some((id, stats)) if *id == head => Some(stats.clone()),
+                _ => {
+                    let stats = metrics::RepoStats::new(&repo)?;
+                    *cache = Some((head, stats.clone()));
+                    Some(stats)
+                }
+            }
+        }
+        // An empty repo has no `master` yet
+        Err(_) => None,
+    };
+    Ok((
+        [(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(stats.as_ref()),
+    )
+        .into_response())
 }
 
 pub async fn assets(Path(path): Path<String>) -> Result<Response> {
@@ -206,6 +528,6 @@ pub async fn assets(Path(path): Path<String>) -> Result<Response> {
         )
             .into_response())
     } else {
-        Ok(http::StatusCode::NOT_FOUND.into_response())
+        Ok(StatusCode::NOT_FOUND.into_response())
     }
 }
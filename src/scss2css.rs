@@ -1,6 +1,106 @@
-use sass_rs::{compile_string, Options};
+use crate::git::{EntryKind, Repo};
 
-pub fn compile(scss: &str) -> String {
-    let css_opts = Options::default();
-    compile_string(scss, css_opts).expect("invalid scss syntax")
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+pub fn compile(scss: &str) -> Result<String> {
+    grass::from_string(scss, &grass::Options::default())
+        .map_err(|e| anyhow::anyhow!("invalid scss syntax: {e}"))
+}
+
+/// Stylesheets of the wiki repo, by name.
+fn repo_styles(repo: &Repo) -> Result<Vec<(String, String)>> {
+    let Ok(tree) = repo.get_tree("css") else {
+        return Ok(vec![]);
+    };
+    let mut styles = vec![];
+    for e in Repo::list_entries(&tree)? {
+        if matches!(e.kind, EntryKind::File)
+            && (e.name.ends_with(".css") || e.name.ends_with(".scss"))
+        {
+            match String::from_utf8(repo.get_blob_from_id(e.id)?) {
+                Ok(content) => styles.push((e.name, content)),
+                Err(err) => tracing::error!("css/{}: {err}", e.name),
+            }
+        }
+    }
+    styles.sort();
+    Ok(styles)
+}
+
+fn push_style(css: &mut String, name: &str, content: &str) {
+    if name.ends_with(".scss") {
+        match compile(content) {
+            Ok(c) => css.push_str(&c),
+            Err(e) => tracing::error!("css/{name}: {e}"),
+        }
+    } else {
+        css.push_str(content);
+    }
+}
+
+/// Site stylesheet: the `builtin` styles followed by the ones in `css/` of
+/// the wiki repo. A repo file with the same name as a built-in one, or with
+/// `.scss` instead of `.css`, replaces it. A broken file is left out.
+pub fn site_css(repo: &Repo, builtin: &[(&str, &str)]) -> Result<String> {
+    let mut styles = repo_styles(repo)?;
+    let mut css = String::new();
+    for (name, builtin) in builtin {
+        let scss = name.replace(".css", ".scss");
+        match styles.iter().position(|(n, _)| n == name || *n == scss) {
+            Some(i) => {
+                let (name, content) = styles.remove(i);
+                push_style(&mut css, &name, &content);
+            }
+            None => css.push_str(builtin),
+        }
+    }
+    for (name, content) in styles {
+        push_style(&mut css, &name, &content);
+    }
+    Ok(css)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::{CommitData, ThreadSafeRepo};
+
+    const BUILTIN: [(&str, &str); 2] = [("base.css", "a{}\n"), ("wiki.css", "b{}\n")];
+
+    fn site_css_with(files: &[(&str, &str)]) -> String {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let repo = ThreadSafeRepo::open(tmp.path().to_str().unwrap())
+            .unwrap()
+            .local();
+        repo.commit(&CommitData {
+            msg: "Styles".to_owned(),
+            author: "ann".to_owned(),
+            added: files
+                .iter()
+                .map(|(p, c)| (p.to_string(), c.to_string()))
+                .collect(),
+            removed: vec![],
+            timestamp: None,
+        })
+        .unwrap();
+        site_css(&repo, &BUILTIN).unwrap()
+    }
+
+    #[test]
+    fn scss_from_repo() {
+        let css = site_css_with(&[("css/wiki.scss", "$c: red;\n.x { .y { color: $c; } }\n")]);
+        assert_eq!(css, "a{}\n.x .y {\n  color: red;\n}\n");
+    }
+
+    #[test]
+    fn plain_css_passthrough() {
+        let css = site_css_with(&[("css/base.css", "c{}\n"), ("css/extra.css", "d{}\n")]);
+        assert_eq!(css, "c{}\nb{}\nd{}\n");
+    }
+
+    #[test]
+    fn invalid_scss_is_left_out() {
+        let css = site_css_with(&[("css/extra.scss", ".x { color: ")]);
+        assert_eq!(css, "a{}\nb{}\n");
+    }
 }
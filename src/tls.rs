@@ -0,0 +1,166 @@
+use axum::extract::Request;
+use axum::http::{header, StatusCode, Uri};
+use axum::response::{IntoResponse, Redirect};
+use axum::Router;
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::Tls;
+
+type Result<T> = std::result::Result<T, anyhow::Error>;
+
+/// Time a client has to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn acceptor(tls: &Tls) -> Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(&tls.cert)
+        .and_then(|c| c.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| anyhow::anyhow!("{}: {e}", tls.cert))?;
+    let key =
+        PrivateKeyDer::from_pem_file(&tls.key).map_err(|e| anyhow::anyhow!("{}: {e}", tls.key))?;
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Connections of a TCP listener after their TLS handshake.
+pub struct TlsListener {
+    connections: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    /// Accept TLS connections on `listener` with the certificate and key of
+    /// `tls`. They are loaded again on SIGHUP.
+    pub fn new(listener: TcpListener, tls: &Tls) -> Result<TlsListener> {
+        let acceptor = Arc::new(RwLock::new(acceptor(tls)?));
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        let reloaded = acceptor.clone();
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                match self::acceptor(&tls) {
+                    Ok(a) => {
+                        *reloaded.write().unwrap() = a;
+                        tracing::info!("reloaded the TLS certificate");
+                    }
+                    Err(e) => tracing::error!("cannot reload the TLS certificate: {e:#}"),
+                }
+            }
+        });
+
+        let local_addr = listener.local_addr()?;
+        let (tx, connections) = mpsc::channel(64);
+        // Handshakes run in their own tasks so slow clients don't block others
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = match listener.accept().await {
+                    Ok(c) => c,
+                    Err(e) => {
+                        tracing::error!("accept error: {e}");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+                let acceptor = acceptor.read().unwrap().clone();
+                let sender = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = sender.send((stream, addr)).await;
+                        }
+                        Ok(Err(e)) => tracing::debug!("TLS handshake with {addr} failed: {e}"),
+                        Err(_) => tracing::debug!("TLS handshake with {addr} timed out"),
+                    }
+                });
+                if tx.is_closed() {
+                    break;
+                }
+            }
+        });
+        Ok(TlsListener {
+            connections,
+            local_addr,
+        })
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.connections.recv().await {
+            Some(c) => c,
+            // The accept loop only stops once this listener is gone
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// Same URL over HTTPS on `port`.
+fn https_url(req: &Request, port: u16) -> Option<String> {
+    let host = req.headers().get(header::HOST)?.to_str().ok()?;
+    let host: Uri = format!("http://{host}").parse().ok()?;
+    let host = host.host()?;
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    Some(match port {
+        443 => format!("https://{host}{path}"),
+        port => format!("https://{host}:{port}{path}"),
+    })
+}
+
+/// Redirect plain HTTP requests on `address` to HTTPS on `port`.
+pub async fn redirect_http(address: &str, port: u16) -> Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    tracing::info!("redirecting HTTP on {address} to HTTPS");
+    let app = Router::new().fallback(move |req: Request| async move {
+        match https_url(&req, port) {
+            Some(url) => Redirect::permanent(&url).into_response(),
+            None => (StatusCode::BAD_REQUEST, "Missing host").into_response(),
+        }
+    });
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            tracing::error!("HTTP redirect server failed: {e}");
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+
+    #[test]
+    fn redirect_url() {
+        let req = Request::builder()
+            .uri("/page/a?x=1")
+            .header(header::HOST, "wiki.example.com:80")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            https_url(&req, 443).as_deref(),
+            Some("https://wiki.example.com/page/a?x=1")
+        );
+        assert_eq!(
+            https_url(&req, 8443).as_deref(),
+            Some("https://wiki.example.com:8443/page/a?x=1")
+        );
+    }
+}